@@ -0,0 +1,223 @@
+// Built in filters, applied to a value with ${name | filter(args) | ...}
+
+mod case;
+pub(crate) mod date;
+mod number;
+mod text;
+
+// A filter takes the value and any arguments, failing with a message
+pub(crate) type Filter = fn(&str, &[&str]) -> Result<String, String>;
+
+pub(crate) fn builtin(name: &str) -> Option<Filter> {
+    let filter: Filter = match name {
+        "upper" => |v, _| Ok(v.to_uppercase()),
+        "lower" => |v, _| Ok(v.to_lowercase()),
+        "capitalize" => capitalize,
+        "trim" => |v, _| Ok(v.trim().to_string()),
+        "replace" => replace,
+        "default" => default,
+        "length" => length,
+        "join" => join,
+        // Marks a value not to be escaped, see Escape
+        "raw" => |v, _| Ok(v.to_string()),
+        "html" => |v, _| Ok(html(v)),
+        "xml" => |v, _| Ok(xml(v)),
+        "url" => |v, _| Ok(url(v)),
+        "json" => |v, _| Ok(json(v)),
+        "shell" => |v, _| Ok(shell(v)),
+        "csv" => |v, _| Ok(csv(v)),
+        "number" => number::number,
+        "round" => number::round,
+        "percent" => number::percent,
+        "sign" => number::sign,
+        "zeropad" => number::zeropad,
+        "date" => date::date,
+        "snake_case" => case::snake,
+        "camel_case" => case::camel,
+        "pascal_case" => case::pascal,
+        "kebab_case" => case::kebab,
+        "screaming_snake_case" => case::screaming_snake,
+        "title_case" => case::title,
+        "indent" => indent,
+        "wrap" => text::wrap,
+        "truncate" => text::truncate,
+        "pad_left" => text::pad_left,
+        "pad_right" => text::pad_right,
+        "center" => text::center,
+        _ => return None
+    };
+
+    Some(filter)
+}
+
+// Filters whose output is already escaped
+pub(crate) fn is_escaper(name: &str) -> bool {
+    matches!(name, "raw" | "html" | "xml" | "url" | "json" | "shell" | "csv")
+}
+
+fn arg<'a>(args: &[&'a str], n: usize) -> Result<&'a str, String> {
+    args.get(n).copied().ok_or_else(|| format!("missing argument {}", n + 1))
+}
+
+fn arg_or<'a>(args: &[&'a str], n: usize, default: &'a str) -> &'a str {
+    args.get(n).copied().unwrap_or(default)
+}
+
+// Largest count accepted, so a template cannot ask for huge allocations
+const MAX_COUNT: usize = 1000;
+
+// Optional argument which counts something, places, width etc.
+fn count_arg(args: &[&str], n: usize, default: usize) -> Result<usize, String> {
+    match args.get(n) {
+        Some(a) => match a.trim().parse() {
+            Ok(count) if count <= MAX_COUNT => Ok(count),
+            Ok(_) => Err(format!("argument {} must be at most {MAX_COUNT}, not {a}", n + 1)),
+            Err(_) => Err(format!("argument {} must be a whole number, not {a}", n + 1))
+        },
+        None => Ok(default)
+    }
+}
+
+fn capitalize(v: &str, _: &[&str]) -> Result<String, String> {
+    let mut chars = v.chars();
+
+    Ok(match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+        None => String::new()
+    })
+}
+
+fn replace(v: &str, args: &[&str]) -> Result<String, String> {
+    Ok(v.replace(arg(args, 0)?, arg(args, 1)?))
+}
+
+fn default(v: &str, args: &[&str]) -> Result<String, String> {
+    if v.is_empty() {
+        Ok(arg(args, 0)?.to_string())
+    } else {
+        Ok(v.to_string())
+    }
+}
+
+// Number of values for a multi-value, otherwise characters
+fn length(v: &str, _: &[&str]) -> Result<String, String> {
+    if v.contains('|') {
+        Ok(v.split('|').count().to_string())
+    } else {
+        Ok(v.chars().count().to_string())
+    }
+}
+
+// Join multi-values with a separator
+fn join(v: &str, args: &[&str]) -> Result<String, String> {
+    Ok(v.split('|').map(|v| v.trim()).collect::<Vec<_>>().join(arg_or(args, 0, "")))
+}
+
+// indent(width), lines after the first, the first is at the placeholder
+fn indent(v: &str, args: &[&str]) -> Result<String, String> {
+    Ok(indent_lines(v, &" ".repeat(count_arg(args, 0, 4)?)))
+}
+
+// Blank lines are left empty
+pub(crate) fn indent_lines(v: &str, indent: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+
+    for (i, line) in v.split('\n').enumerate() {
+        if i > 0 {
+            s.push('\n');
+            if !line.trim().is_empty() {
+                s.push_str(indent);
+            }
+        }
+        s.push_str(line);
+    }
+
+    s
+}
+
+pub(crate) fn html(v: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+
+    for c in v.chars() {
+        match c {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '>' => s.push_str("&gt;"),
+            '"' => s.push_str("&quot;"),
+            '\'' => s.push_str("&#39;"),
+            c => s.push(c)
+        }
+    }
+
+    s
+}
+
+// Suitable for attribute values, so white space is kept as character references
+pub(crate) fn xml(v: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+
+    for c in v.chars() {
+        match c {
+            '&' => s.push_str("&amp;"),
+            '<' => s.push_str("&lt;"),
+            '>' => s.push_str("&gt;"),
+            '"' => s.push_str("&quot;"),
+            '\'' => s.push_str("&apos;"),
+            '\n' => s.push_str("&#10;"),
+            '\r' => s.push_str("&#13;"),
+            '\t' => s.push_str("&#9;"),
+            c => s.push(c)
+        }
+    }
+
+    s
+}
+
+// Percent encode all but the unreserved characters of RFC 3986
+pub(crate) fn url(v: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+
+    for b in v.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => s.push(b as char),
+            b => s.push_str(&format!("%{b:02X}"))
+        }
+    }
+
+    s
+}
+
+// Contents of a JSON string, the quotes are not added
+pub(crate) fn json(v: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+
+    for c in v.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            '\u{8}' => s.push_str("\\b"),
+            '\u{c}' => s.push_str("\\f"),
+            c if c.is_control() => s.push_str(&format!("\\u{:04x}", c as u32)),
+            c => s.push(c)
+        }
+    }
+
+    s
+}
+
+// POSIX single quoting, a quote ends the string, is escaped and the string restarts
+pub(crate) fn shell(v: &str) -> String {
+    format!("'{}'", v.replace('\'', "'\\''"))
+}
+
+// RFC 4180 field, quoted only when it has to be
+pub(crate) fn csv(v: &str) -> String {
+    if v.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", v.replace('"', "\"\""))
+    } else {
+        v.to_string()
+    }
+}
@@ -0,0 +1,68 @@
+// Identifier case conversion, for code generation
+
+// Words are split at non alphanumerics, lower to upper case changes and the end
+// of an acronym, "HTTPServer" is HTTP Server. Digits belong to the word before.
+fn words(v: &str) -> Vec<String> {
+    let chars: Vec<char> = v.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for (i, c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if !word.is_empty() && c.is_uppercase() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_lower) {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.push(*c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words.into_iter().map(|w| w.to_lowercase()).collect()
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new()
+    }
+}
+
+pub(crate) fn snake(v: &str, _: &[&str]) -> Result<String, String> {
+    Ok(words(v).join("_"))
+}
+
+pub(crate) fn kebab(v: &str, _: &[&str]) -> Result<String, String> {
+    Ok(words(v).join("-"))
+}
+
+pub(crate) fn screaming_snake(v: &str, _: &[&str]) -> Result<String, String> {
+    Ok(words(v).join("_").to_uppercase())
+}
+
+pub(crate) fn pascal(v: &str, _: &[&str]) -> Result<String, String> {
+    Ok(words(v).iter().map(|w| capitalize(w)).collect())
+}
+
+pub(crate) fn camel(v: &str, _: &[&str]) -> Result<String, String> {
+    Ok(words(v).iter()
+        .enumerate()
+        .map(|(i, w)| if i == 0 { w.clone() } else { capitalize(w) })
+        .collect())
+}
+
+pub(crate) fn title(v: &str, _: &[&str]) -> Result<String, String> {
+    Ok(words(v).iter().map(|w| capitalize(w)).collect::<Vec<_>>().join(" "))
+}
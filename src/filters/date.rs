@@ -0,0 +1,184 @@
+// Dates as seconds since the epoch with a UTC offset, in the proleptic Gregorian calendar
+
+use super::arg_or;
+
+const DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+const MONTHS: [&str; 12] = ["January", "February", "March", "April", "May", "June", "July",
+    "August", "September", "October", "November", "December"];
+// Epoch seconds of 0000-01-01T00:00:00 and 9999-12-31T23:59:59, four digit years
+const MIN_SECS: i64 = -62_167_219_200;
+const MAX_SECS: i64 = 253_402_300_799;
+
+// Days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+fn days_in_month(y: i64, m: i64) -> i64 {
+    days_from_civil(if m == 12 { y + 1 } else { y }, if m == 12 { 1 } else { m + 1 }, 1) - days_from_civil(y, m, 1)
+}
+
+// Fixed width number, None if not all digits
+fn digits(s: &str, from: usize, len: usize) -> Option<i64> {
+    let s = s.get(from..from + len)?;
+
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+// Epoch seconds, or ISO-8601 date with optional time and offset, as (seconds, offset)
+fn parse(v: &str) -> Result<(i64, i64), String> {
+    let v = v.trim();
+    let invalid = || format!("{v} is not a date");
+
+    if let Ok(secs) = v.parse::<i64>() {
+        return if (MIN_SECS..=MAX_SECS).contains(&secs) { Ok((secs, 0)) } else { Err(invalid()) };
+    }
+    if let Ok(secs) = v.parse::<f64>() {
+        let secs = secs.floor();
+
+        // Also false for NaN and infinities
+        return if secs >= MIN_SECS as f64 && secs <= MAX_SECS as f64 { Ok((secs as i64, 0)) } else { Err(invalid()) };
+    }
+
+    let (y, m, d) = match (digits(v, 0, 4), v.get(4..5), digits(v, 5, 2), v.get(7..8), digits(v, 8, 2)) {
+        (Some(y), Some("-"), Some(m), Some("-"), Some(d)) if (1..=12).contains(&m) && d >= 1 && d <= days_in_month(y, m) => (y, m, d),
+        _ => return Err(invalid())
+    };
+    let mut secs = days_from_civil(y, m, d) * 86400;
+    let mut offset = 0;
+    let mut rest = &v[10..];
+
+    if let Some(time) = rest.strip_prefix(['T', 't', ' ']) {
+        let (h, min) = match (digits(time, 0, 2), time.get(2..3), digits(time, 3, 2)) {
+            (Some(h), Some(":"), Some(min)) if h < 24 && min < 60 => (h, min),
+            _ => return Err(invalid())
+        };
+        rest = &time[5..];
+        let s = match rest.strip_prefix(':') {
+            Some(s) => {
+                rest = s.get(2..).ok_or_else(invalid)?;
+                digits(s, 0, 2).filter(|s| *s <= 60).ok_or_else(invalid)?
+            },
+            None => 0
+        };
+        // Fractions of a second are dropped
+        if let Some(frac) = rest.strip_prefix('.') {
+            rest = frac.trim_start_matches(|c: char| c.is_ascii_digit());
+        }
+        secs += h * 3600 + min * 60 + s;
+
+        offset = match rest {
+            "" | "Z" | "z" => 0,
+            _ => {
+                let (sign, zone) = match (rest.strip_prefix('+'), rest.strip_prefix('-')) {
+                    (Some(zone), _) => (1, zone),
+                    (_, Some(zone)) => (-1, zone),
+                    _ => return Err(invalid())
+                };
+                let zone = zone.replace(':', "");
+                let (h, min) = match zone.len() {
+                    2 => (digits(&zone, 0, 2), Some(0)),
+                    4 => (digits(&zone, 0, 2), digits(&zone, 2, 2)),
+                    _ => (None, None)
+                };
+                match (h, min) {
+                    (Some(h), Some(min)) if h < 24 && min < 60 => sign * (h * 3600 + min * 60),
+                    _ => return Err(invalid())
+                }
+            }
+        };
+        rest = "";
+    }
+    if !rest.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((secs - offset, offset))
+}
+
+fn format(secs: i64, offset: i64, pattern: &str) -> Result<String, String> {
+    let local = secs + offset;
+    let days = local.div_euclid(86400);
+    let sod = local.rem_euclid(86400);
+    let (y, m, d) = civil_from_days(days);
+    let (h, min, s) = (sod / 3600, sod / 60 % 60, sod % 60);
+    let weekday = (days + 4).rem_euclid(7) as usize;
+    let zone = |colon: &str| format!("{}{:02}{colon}{:02}", if offset < 0 { '-' } else { '+' }, offset.abs() / 3600, offset.abs() / 60 % 60);
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let spec = match chars.next() {
+            Some(':') => match chars.next() {
+                Some('z') => zone(":"),
+                _ => return Err("unknown format %:".into())
+            },
+            Some('Y') => format!("{y:04}"),
+            Some('y') => format!("{:02}", y.rem_euclid(100)),
+            Some('m') => format!("{m:02}"),
+            Some('d') => format!("{d:02}"),
+            Some('e') => format!("{d:2}"),
+            Some('j') => format!("{:03}", days - days_from_civil(y, 1, 1) + 1),
+            Some('H') => format!("{h:02}"),
+            Some('I') => format!("{:02}", (h + 11) % 12 + 1),
+            Some('p') => if h < 12 { "AM" } else { "PM" }.to_string(),
+            Some('M') => format!("{min:02}"),
+            Some('S') => format!("{s:02}"),
+            Some('A') => DAYS[weekday].to_string(),
+            Some('a') => DAYS[weekday][..3].to_string(),
+            Some('B') => MONTHS[m as usize - 1].to_string(),
+            Some('b') => MONTHS[m as usize - 1][..3].to_string(),
+            Some('u') => (if weekday == 0 { 7 } else { weekday }).to_string(),
+            Some('w') => weekday.to_string(),
+            Some('s') => secs.to_string(),
+            Some('z') => zone(""),
+            Some('F') => format!("{y:04}-{m:02}-{d:02}"),
+            Some('T') => format!("{h:02}:{min:02}:{s:02}"),
+            Some('%') => "%".to_string(),
+            Some(c) => return Err(format!("unknown format %{c}")),
+            None => return Err("format ends with %".into())
+        };
+        out.push_str(&spec);
+    }
+
+    Ok(out)
+}
+
+// date(pattern), strftime like, in the date's own offset
+pub(crate) fn date(v: &str, args: &[&str]) -> Result<String, String> {
+    let (secs, offset) = parse(v)?;
+
+    format(secs, offset, arg_or(args, 0, "%Y-%m-%d"))
+}
+
+// ISO-8601 UTC timestamp
+pub(crate) fn iso(secs: i64) -> String {
+    format(secs, 0, "%Y-%m-%dT%H:%M:%SZ").unwrap_or_default()
+}
@@ -0,0 +1,160 @@
+// Number formatting, done on the decimal digits so rounding is exact
+
+use super::{arg_or, count_arg};
+
+// A decimal number as digits, int has no leading zeros
+struct Decimal {
+    negative: bool,
+    int: String,
+    frac: String
+}
+
+impl Decimal {
+    fn parse(v: &str) -> Result<Self, String> {
+        let v = v.trim();
+        let (negative, digits) = match v.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, v.strip_prefix('+').unwrap_or(v))
+        };
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+
+        if (int.is_empty() && frac.is_empty()) || !is_digits(int) || !is_digits(frac) {
+            // Exponents, which Display for f64 never uses
+            return match v.parse::<f64>() {
+                Ok(f) if f.is_finite() => Decimal::parse(&f.to_string()),
+                _ => Err(format!("{v} is not a number"))
+            };
+        }
+
+        let mut d = Decimal { negative, int: int.trim_start_matches('0').to_string(), frac: frac.to_string() };
+        d.negative &= !d.is_zero();
+
+        Ok(d)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.int.chars().chain(self.frac.chars()).all(|c| c == '0')
+    }
+
+    // Multiply by 10^n
+    fn shift(&mut self, n: usize) {
+        while self.frac.len() < n {
+            self.frac.push('0');
+        }
+        self.int.push_str(&self.frac[..n]);
+        self.frac = self.frac[n..].to_string();
+        self.int = self.int.trim_start_matches('0').to_string();
+    }
+
+    // Round half away from zero
+    fn round(&mut self, places: usize) {
+        if self.frac.len() <= places {
+            while self.frac.len() < places {
+                self.frac.push('0');
+            }
+            return;
+        }
+
+        let up = self.frac.as_bytes()[places] >= b'5';
+        self.frac.truncate(places);
+
+        if up {
+            let mut digits: Vec<u8> = self.int.bytes().chain(self.frac.bytes()).collect();
+            let mut i = digits.len();
+
+            loop {
+                if i == 0 {
+                    digits.insert(0, b'1');
+                    break;
+                }
+                i -= 1;
+                if digits[i] == b'9' {
+                    digits[i] = b'0';
+                } else {
+                    digits[i] += 1;
+                    break;
+                }
+            }
+            let split = digits.len() - places;
+            self.int = String::from_utf8_lossy(&digits[..split]).to_string();
+            self.frac = String::from_utf8_lossy(&digits[split..]).to_string();
+        }
+        self.negative &= !self.is_zero();
+    }
+
+    fn format(&self, sep: &str, point: &str) -> String {
+        let int = if self.int.is_empty() { "0" } else { &self.int };
+        let mut s = String::new();
+
+        if self.negative {
+            s.push('-');
+        }
+        for (i, c) in int.chars().enumerate() {
+            if i > 0 && (int.len() - i) % 3 == 0 {
+                s.push_str(sep);
+            }
+            s.push(c);
+        }
+        if !self.frac.is_empty() {
+            s.push_str(point);
+            s.push_str(&self.frac);
+        }
+
+        s
+    }
+}
+
+// number(places, separator, point), thousands separated, places rounded if given
+pub(crate) fn number(v: &str, args: &[&str]) -> Result<String, String> {
+    let mut d = Decimal::parse(v)?;
+
+    if !args.is_empty() {
+        d.round(count_arg(args, 0, 0)?);
+    }
+
+    Ok(d.format(arg_or(args, 1, ","), arg_or(args, 2, ".")))
+}
+
+// round(places)
+pub(crate) fn round(v: &str, args: &[&str]) -> Result<String, String> {
+    let mut d = Decimal::parse(v)?;
+
+    d.round(count_arg(args, 0, 0)?);
+
+    Ok(d.format("", "."))
+}
+
+// percent(places), a fraction as a percentage
+pub(crate) fn percent(v: &str, args: &[&str]) -> Result<String, String> {
+    let mut d = Decimal::parse(v)?;
+
+    d.shift(2);
+    d.round(count_arg(args, 0, 0)?);
+
+    Ok(d.format(arg_or(args, 1, ","), ".") + "%")
+}
+
+// Explicit + for positive numbers, works on formatted numbers
+pub(crate) fn sign(v: &str, _: &[&str]) -> Result<String, String> {
+    let v = v.trim();
+
+    match v.chars().next() {
+        Some(c) if c.is_ascii_digit() && v.chars().any(|c| c.is_ascii_digit() && c != '0') =>
+            Ok(format!("+{v}")),
+        _ => Ok(v.to_string())
+    }
+}
+
+// zeropad(width), zeros after any sign up to the width
+pub(crate) fn zeropad(v: &str, args: &[&str]) -> Result<String, String> {
+    let v = v.trim();
+    let width = count_arg(args, 0, 0)?;
+    let (sign, digits) = match v.strip_prefix(['-', '+']) {
+        Some(digits) => (&v[..1], digits),
+        None => ("", v)
+    };
+    let zeros = width.saturating_sub(sign.len() + digits.chars().count());
+
+    Ok(format!("{sign}{}{digits}", "0".repeat(zeros)))
+}
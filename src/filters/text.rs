@@ -0,0 +1,73 @@
+// Text layout, widths are in characters not bytes
+
+use super::{arg_or, count_arg};
+
+// wrap(width), at white space, each line separately. Longer words are not broken.
+pub(crate) fn wrap(v: &str, args: &[&str]) -> Result<String, String> {
+    let width = count_arg(args, 0, 80)?;
+    let mut lines = Vec::new();
+
+    for line in v.lines() {
+        let mut wrapped = String::new();
+        let mut len = 0;
+
+        for word in line.split_whitespace() {
+            let n = word.chars().count();
+
+            if len > 0 && len + 1 + n > width {
+                lines.push(std::mem::take(&mut wrapped));
+                len = 0;
+            }
+            if len > 0 {
+                wrapped.push(' ');
+                len += 1;
+            }
+            wrapped.push_str(word);
+            len += n;
+        }
+        lines.push(wrapped);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+// truncate(length, ellipsis), the result including the ellipsis is at most length
+pub(crate) fn truncate(v: &str, args: &[&str]) -> Result<String, String> {
+    let length = count_arg(args, 0, 80)?;
+    let ellipsis = arg_or(args, 1, "…");
+
+    if v.chars().count() <= length {
+        return Ok(v.to_string());
+    }
+
+    let keep = length.saturating_sub(ellipsis.chars().count());
+    let mut s: String = v.chars().take(keep).collect();
+    s.push_str(ellipsis);
+
+    Ok(s.chars().take(length).collect())
+}
+
+fn pad(v: &str, args: &[&str], left: fn(usize) -> usize) -> Result<String, String> {
+    let width = count_arg(args, 0, 0)?;
+    let fill = arg_or(args, 1, " ").chars().next().unwrap_or(' ');
+    let n = width.saturating_sub(v.chars().count());
+    let left = left(n);
+    let fill = |n| std::iter::repeat_n(fill, n).collect::<String>();
+
+    Ok(format!("{}{v}{}", fill(left), fill(n - left)))
+}
+
+// pad_left(width, fill), right aligned
+pub(crate) fn pad_left(v: &str, args: &[&str]) -> Result<String, String> {
+    pad(v, args, |n| n)
+}
+
+// pad_right(width, fill), left aligned
+pub(crate) fn pad_right(v: &str, args: &[&str]) -> Result<String, String> {
+    pad(v, args, |_| 0)
+}
+
+// center(width, fill), any odd fill goes on the right
+pub(crate) fn center(v: &str, args: &[&str]) -> Result<String, String> {
+    pad(v, args, |n| n / 2)
+}
@@ -1,8 +1,683 @@
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::path::{Component, Path, PathBuf};
+
+mod filters;
 
 // Default delimiters
 const START_DLIM: &str = "${";
 const END_DLIM: &str = "}";
+// Raw content is replaced until rendering is done by a placeholder of
+// private use characters around the render's key and an index
+const RAW_START: char = '\u{E000}';
+const RAW_END: char = '\u{E001}';
+
+#[derive(Debug, Clone, PartialEq)]
+/// A value supplied to a template, plain strings are enough for most uses.
+/// Lists and records can be iterated with ${for ...}, record fields are
+/// referenced with dotted names such as ${row.name}.
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use stemplate::{Template, Value};
+/// let mut row = HashMap::new();
+/// row.insert("name", "Pen");
+/// row.insert("price", "1.20");
+/// let mut args = HashMap::new();
+/// args.insert("rows", Value::from(vec![Value::from(row)]));
+/// let s = Template::new("${for row in rows}${row.name} ${row.price}${end}").render(&args);
+/// assert_eq!(s, "Pen 1.20");
+/// ```
+pub enum Value {
+    Str(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>)
+}
+
+impl Value {
+    /// Find a field by (possibly dotted) path, list elements are indexed by number
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        fn field<'v>(v: &'v Value, name: &str) -> Option<&'v Value> {
+            match v {
+                Value::Map(m) => m.get(name),
+                Value::List(l) => l.get(name.parse::<usize>().ok()?),
+                Value::Str(_) => None
+            }
+        }
+
+        match field(self, path) {
+            Some(v) => Some(v),
+            None => {
+                let (head, rest) = path.split_once('.')?;
+
+                field(self, head)?.get(rest)
+            }
+        }
+    }
+
+    // Values to iterate over, strings are '|' separated multi-values
+    fn items(&self) -> Vec<Value> {
+        match self {
+            Value::Str(s) => split_values(s).into_iter().map(Value::Str).collect(),
+            Value::List(l) => l.clone(),
+            Value::Map(m) => m.values().cloned().collect()
+        }
+    }
+}
+
+/// Strings are shown as is, lists as '|' separated multi-values and
+/// records as nothing
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => f.write_str(s),
+            Value::List(l) => {
+                for (i, v) in l.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("|")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                Ok(())
+            },
+            Value::Map(_) => Ok(())
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(l: Vec<T>) -> Self {
+        Value::List(l.into_iter().map(|v| v.into()).collect())
+    }
+}
+
+impl<K: ToString, T: Into<Value>> From<HashMap<K, T>> for Value {
+    fn from(m: HashMap<K, T>) -> Self {
+        Value::Map(m.into_iter().map(|(k, v)| (k.to_string(), v.into())).collect())
+    }
+}
+
+impl<K: ToString, T: Into<Value>> From<BTreeMap<K, T>> for Value {
+    fn from(m: BTreeMap<K, T>) -> Self {
+        Value::Map(m.into_iter().map(|(k, v)| (k.to_string(), v.into())).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Errors which stop a template being rendered
+pub enum Error {
+    /// Multi-values combined with the strict strategy differ in length, (name, length)
+    LengthMismatch(Vec<(String, usize)>),
+    /// A macro was called but has not been defined
+    UnknownMacro(String),
+    /// A macro calls itself, the chain of calls ending with the repeat
+    MacroRecursion(Vec<String>),
+    /// A filter was used but does not exist
+    UnknownFilter(String),
+    /// A filter failed, (name, reason)
+    Filter(String, String),
+    /// An include would read outside the include paths, (file, reason)
+    IncludeRejected(String, String),
+    /// A file includes itself, the chain of includes ending with the repeat
+    IncludeCycle(Vec<String>)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LengthMismatch(lens) => {
+                let lens: Vec<String> = lens.iter()
+                    .map(|(k, n)| format!("{k}={n}"))
+                    .collect();
+
+                write!(f, "multi-values differ in length: {}", lens.join(", "))
+            },
+            Error::UnknownMacro(name) => write!(f, "unknown macro: {name}"),
+            Error::MacroRecursion(chain) => write!(f, "macro recursion: {}", chain.join(" -> ")),
+            Error::UnknownFilter(name) => write!(f, "unknown filter: {name}"),
+            Error::Filter(name, reason) => write!(f, "filter {name} failed: {reason}"),
+            Error::IncludeRejected(file, reason) => write!(f, "include {file} rejected: {reason}"),
+            Error::IncludeCycle(chain) => write!(f, "include cycle: {}", chain.join(" -> "))
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// How '*' combines the multi-values referenced in its content
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zip {
+    Shortest,
+    Longest,
+    Strict,
+    Product
+}
+
+impl Zip {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "shortest" => Some(Zip::Shortest),
+            "longest" => Some(Zip::Longest),
+            "strict" => Some(Zip::Strict),
+            "product" => Some(Zip::Product),
+            _ => None
+        }
+    }
+
+    // Index into each list for every instance rendered, None when a list is exhausted
+    fn rows(&self, lens: &[(&str, usize)]) -> Result<Vec<Vec<Option<usize>>>, Error> {
+        let min = lens.iter().map(|(_, n)| *n).min().unwrap_or(0);
+        let max = lens.iter().map(|(_, n)| *n).max().unwrap_or(0);
+
+        match self {
+            Zip::Strict if min != max =>
+                Err(Error::LengthMismatch(lens.iter().map(|(k, n)| (k.to_string(), *n)).collect())),
+            Zip::Shortest | Zip::Strict =>
+                Ok((0 .. min).map(|i| vec![Some(i); lens.len()]).collect()),
+            Zip::Longest =>
+                Ok((0 .. max).map(|i| lens.iter().map(|(_, n)| (i < *n).then_some(i)).collect()).collect()),
+            Zip::Product => {
+                let mut rows: Vec<Vec<Option<usize>>> = vec![Vec::new()];
+
+                for (_, n) in lens {
+                    rows = rows.iter()
+                        .flat_map(|row| (0 .. *n).map(move |i| {
+                            let mut row = row.clone();
+                            row.push(Some(i));
+                            row
+                        }))
+                        .collect();
+                }
+                Ok(rows)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// How values substituted into a template are escaped
+pub enum Escape {
+    /// Inserted as is
+    #[default]
+    None,
+    /// HTML special characters are replaced by entities
+    Html,
+    /// XML attribute values, white space is also replaced
+    Xml,
+    /// Percent encoded for URLs and query strings
+    Url,
+    /// Contents of a JSON string
+    Json,
+    /// POSIX shell single quoted
+    Shell,
+    /// CSV field, quoted if necessary
+    Csv
+}
+
+impl Escape {
+    fn apply(&self, v: &str) -> String {
+        match self {
+            Escape::None => v.to_string(),
+            Escape::Html => filters::html(v),
+            Escape::Xml => filters::xml(v),
+            Escape::Url => filters::url(v),
+            Escape::Json => filters::json(v),
+            Escape::Shell => filters::shell(v),
+            Escape::Csv => filters::csv(v)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which files ${!file} may include, by default those with a .inc extension.
+/// Other files fail with Error::IncludeRejected.
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use stemplate::{Environment, Error, IncludePolicy, Template};
+/// let mut env = Environment::new();
+/// env.include_path("templates").include_policy(IncludePolicy::extensions(&["html"]));
+/// let mut args = HashMap::new();
+/// args.insert("title", "Home");
+/// let s = Template::new("${!title.html}").render_with(&env, &args);
+/// assert_eq!(s, "<h2>Home</h2>");
+/// let e = Template::new("${!page.inc}").try_render_with(&env, &args);
+/// assert_eq!(e, Err(Error::IncludeRejected("page.inc".into(), "extension not allowed".into())));
+/// ```
+pub struct IncludePolicy {
+    extensions: Vec<String>,
+    enabled: bool
+}
+
+impl Default for IncludePolicy {
+    fn default() -> Self {
+        Self::extensions(&[".inc"])
+    }
+}
+
+impl IncludePolicy {
+    /// Allow only files with one of these extensions, such as ".html" or "html"
+    pub fn extensions(extensions: &[&str]) -> Self {
+        Self { extensions: extensions.iter().map(|e| dotted(e)).collect(), enabled: true }
+    }
+
+    /// No files are included, for untrusted templates. Includes and extends
+    /// fail with Error::IncludeRejected.
+    pub fn disabled() -> Self {
+        Self { enabled: false, ..Self::default() }
+    }
+
+    /// Allow another extension
+    pub fn allow(&mut self, extension: &str) -> &mut Self {
+        self.extensions.push(dotted(extension));
+
+        self
+    }
+
+    fn allows(&self, file: &str) -> bool {
+        match Path::new(file).extension().and_then(|e| e.to_str()) {
+            Some(extension) => self.extensions.contains(&dotted(extension)),
+            None => false
+        }
+    }
+}
+
+// Extensions are kept with a leading '.'
+fn dotted(extension: &str) -> String {
+    format!(".{}", extension.trim_start_matches('.'))
+}
+
+/// Finds the source of included files, by default from the filesystem
+pub trait IncludeResolver: Send + Sync {
+    /// Look up `name` included from the file `from`, None at the top level.
+    /// Returns the file's identity, the same however it is named, and its
+    /// source. The identity is `from` for its own includes and detects include
+    /// cycles. Ok(None) if there is no such file.
+    fn resolve(&self, name: &str, from: Option<&str>) -> Result<Option<(String, String)>, Error>;
+}
+
+#[derive(Debug, Clone, Default)]
+/// Includes read from directories, relative to the including file first.
+/// A file's identity is its canonical path.
+/// Absolute paths, ".." and symbolic links leading outside the directories
+/// are rejected.
+pub struct FileResolver {
+    paths: Vec<PathBuf>
+}
+
+impl FileResolver {
+    /// Search the current directory until a path is added
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a directory to search
+    pub fn path<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.paths.push(dir.into());
+
+        self
+    }
+}
+
+impl IncludeResolver for FileResolver {
+    fn resolve(&self, name: &str, from: Option<&str>) -> Result<Option<(String, String)>, Error> {
+        let rejected = |reason: &str| Error::IncludeRejected(name.to_string(), reason.to_string());
+
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(_) | Component::CurDir => (),
+                Component::ParentDir => return Err(rejected("parent directory")),
+                Component::RootDir | Component::Prefix(_) => return Err(rejected("absolute path"))
+            }
+        }
+
+        let current = from.and_then(|from| Path::new(from).parent()).map(PathBuf::from);
+        let paths = if self.paths.is_empty() { vec![PathBuf::from(".")] } else { self.paths.clone() };
+        let path = match current.into_iter().chain(paths.iter().cloned()).map(|dir| dir.join(name)).find(|path| path.is_file()) {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+
+        // Symbolic links may point anywhere
+        let real = path.canonicalize().map_err(|e| rejected(&e.to_string()))?;
+        if !paths.iter().filter_map(|dir| dir.canonicalize().ok()).any(|dir| real.starts_with(dir)) {
+            return Err(rejected("outside the include paths"));
+        }
+
+        Ok(std::fs::read_to_string(&path).ok().map(|content| (real.display().to_string(), content)))
+    }
+}
+
+/// Named templates held in memory, for example embedded with include_str!.
+/// Names are looked up relative to the including template's "directory"
+/// first, the part of its name before the last '/'.
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use stemplate::{Environment, Template};
+/// let mut env = Environment::new();
+/// env.include_resolver(HashMap::from([
+///     ("page.inc", "<body>${!parts/nav.inc}</body>"),
+///     ("parts/nav.inc", "<nav>${!links.inc}</nav>"),
+///     ("parts/links.inc", "${site}")
+/// ]));
+/// let mut args = HashMap::new();
+/// args.insert("site", "Home");
+/// let s = Template::new("${!page.inc}").render_with(&env, &args);
+/// assert_eq!(s, "<body><nav>Home</nav></body>");
+/// ```
+impl<K, V> IncludeResolver for HashMap<K, V>
+where
+    K: Borrow<str> + Eq + Hash + Send + Sync,
+    V: AsRef<str> + Send + Sync
+{
+    fn resolve(&self, name: &str, from: Option<&str>) -> Result<Option<(String, String)>, Error> {
+        let relative = from.and_then(|from| from.rsplit_once('/')).map(|(dir, _)| format!("{dir}/{name}"));
+
+        let found = relative.iter().map(|n| n.as_str()).chain([name])
+            .find_map(|n| self.get(n).map(|source| (n.to_string(), source.as_ref().to_string())));
+
+        Ok(found)
+    }
+}
+
+type CustomFilter = Box<dyn Fn(&str, &[&str]) -> Result<String, String> + Send + Sync>;
+type Clock = Box<dyn Fn() -> i64 + Send + Sync>;
+
+#[derive(Default)]
+/// Settings shared by templates, such as custom filters
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use stemplate::{Environment, Template};
+/// let mut env = Environment::new();
+/// env.register_filter("currency", |v, args| {
+///     let v: f64 = v.parse().map_err(|_| format!("{v} is not a number"))?;
+///     Ok::<_, String>(format!("{}{v:.2}", args.first().unwrap_or(&"£")))
+/// });
+/// let mut args = HashMap::new();
+/// args.insert("price", "3.5");
+/// let s = Template::new("${price | currency} ${price | currency(\"$\")}").render_with(&env, &args);
+/// assert_eq!(s, "£3.50 $3.50");
+/// ```
+pub struct Environment {
+    filters: HashMap<String, CustomFilter>,
+    escape: Escape,
+    clock: Option<Clock>,
+    indent: bool,
+    files: FileResolver,
+    resolver: Option<Box<dyn IncludeResolver>>,
+    include_policy: IncludePolicy
+}
+
+impl Environment {
+    /// Create an environment with only the built in filters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a filter, used in pipelines as ${name | filter(args)}. A filter
+    /// of the same name as a built in filter replaces it.
+    pub fn register_filter<F, E>(&mut self, name: &str, filter: F) -> &mut Self
+    where
+        F: Fn(&str, &[&str]) -> Result<String, E> + Send + Sync + 'static,
+        E: fmt::Display
+    {
+        self.filters.insert(name.to_string(), Box::new(move |v, args| filter(v, args).map_err(|e| e.to_string())));
+
+        self
+    }
+
+    /// Escape every substituted value, the = modifier (${=name}) or the raw
+    /// filter (${name | raw}) insert a value as is. Values already passed
+    /// through an escaping filter, html, xml, url, json, shell or csv, are not
+    /// escaped again.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use stemplate::{Environment, Escape, Template};
+    /// let mut env = Environment::new();
+    /// env.autoescape(Escape::Html);
+    /// let mut args = HashMap::new();
+    /// args.insert("comment", "<script>alert('hi')</script>");
+    /// let s = Template::new("<p>${comment}</p>").render_with(&env, &args);
+    /// assert_eq!(s, "<p>&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt;</p>");
+    /// ```
+    pub fn autoescape(&mut self, escape: Escape) -> &mut Self {
+        self.escape = escape;
+
+        self
+    }
+
+    /// Indent each line after the first of a multi-line value or include to
+    /// the column of its placeholder, to keep YAML, Python etc. valid.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use stemplate::{Environment, Template};
+    /// let mut env = Environment::new();
+    /// env.auto_indent(true);
+    /// let mut args = HashMap::new();
+    /// args.insert("body", "x = 1\nreturn x");
+    /// let s = Template::new("def f():\n    ${body}").render_with(&env, &args);
+    /// assert_eq!(s, "def f():\n    x = 1\n    return x");
+    /// ```
+    pub fn auto_indent(&mut self, indent: bool) -> &mut Self {
+        self.indent = indent;
+
+        self
+    }
+
+    /// Replace the system clock used for ${now}, seconds since the epoch.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use stemplate::{Environment, Template};
+    /// let mut env = Environment::new();
+    /// env.clock(|| 1_700_000_000);
+    /// let args: HashMap<&str, &str> = HashMap::new();
+    /// let s = Template::new("${now} ${now | date(\"%A %e %B %Y\")}").render_with(&env, &args);
+    /// assert_eq!(s, "2023-11-14T22:13:20Z Tuesday 14 November 2023");
+    /// ```
+    pub fn clock<F: Fn() -> i64 + Send + Sync + 'static>(&mut self, clock: F) -> &mut Self {
+        self.clock = Some(Box::new(clock));
+
+        self
+    }
+
+    /// Add a directory to search for included files, after the directory of
+    /// the including file. Without any, includes are found from the current
+    /// directory.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use stemplate::{Environment, Template};
+    /// let mut env = Environment::new();
+    /// env.include_path("templates");
+    /// let mut args = HashMap::new();
+    /// args.insert("title", "Home");
+    /// let s = Template::new("${!page.inc}").render_with(&env, &args);
+    /// assert_eq!(s, "<h1>Home</h1>");
+    /// ```
+    pub fn include_path<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.files.path(dir);
+
+        self
+    }
+
+    /// Find includes with a resolver instead of the filesystem, such as a
+    /// HashMap of names to templates
+    pub fn include_resolver<R: IncludeResolver + 'static>(&mut self, resolver: R) -> &mut Self {
+        self.resolver = Some(Box::new(resolver));
+
+        self
+    }
+
+    /// Replace the policy deciding which files may be included
+    pub fn include_policy(&mut self, policy: IncludePolicy) -> &mut Self {
+        self.include_policy = policy;
+
+        self
+    }
+
+    fn now(&self) -> i64 {
+        match &self.clock {
+            Some(clock) => clock(),
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0)
+        }
+    }
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Environment")
+            .field("filters", &self.filters.keys().collect::<Vec<_>>())
+            .field("escape", &self.escape)
+            .field("clock", &self.clock.is_some())
+            .field("indent", &self.indent)
+            .field("include_paths", &self.files.paths)
+            .field("resolver", &self.resolver.is_some())
+            .field("include_policy", &self.include_policy)
+            .finish()
+    }
+}
+
+// Shared by everything rendered in one call, including nested values and includes
+struct State<'e> {
+    env: &'e Environment,
+    // Escaping of substituted values, off while values are rendered for later use
+    escape: Escape,
+    // Index of the next value for each '#' cycle
+    counters: HashMap<String, usize>,
+    // Variables assigned in the template
+    lets: HashMap<String, Value>,
+    // Loop variables, macro and include arguments, innermost last, which hide lets
+    scopes: Vec<HashMap<String, Value>>,
+    // Macros defined in the template, name -> (parameters, body)
+    macros: HashMap<String, (Vec<String>, String)>,
+    // Macros being called, innermost last
+    calls: Vec<String>,
+    // Overriding bodies of each named block, most derived template first
+    blocks: HashMap<String, Vec<String>>,
+    // Bodies ${super} renders for the blocks being rendered
+    supers: Vec<Vec<String>>,
+    // Files being included, innermost last
+    includes: Vec<String>,
+    // Raw content, and the key which makes its placeholders unique to this render
+    raws: Vec<String>,
+    key: u64
+}
+
+impl<'e> State<'e> {
+    fn new(env: &'e Environment) -> Self {
+        Self {
+            env,
+            escape: env.escape,
+            counters: HashMap::new(),
+            lets: HashMap::new(),
+            scopes: Vec::new(),
+            macros: HashMap::new(),
+            calls: Vec::new(),
+            blocks: HashMap::new(),
+            supers: Vec::new(),
+            includes: Vec::new(),
+            raws: Vec::new(),
+            key: RandomState::new().hash_one(0)
+        }
+    }
+
+    // Keep content out of further rendering, returning its placeholder
+    fn stash(&mut self, content: String) -> String {
+        let placeholder = format!("{RAW_START}{:x}:{}{RAW_END}", self.key, self.raws.len());
+
+        self.raws.push(content);
+
+        placeholder
+    }
+
+    // Put back the content of this render's placeholders, None if there are none
+    fn unstash(&self, text: &str) -> Option<String> {
+        let prefix = format!("{RAW_START}{:x}:", self.key);
+
+        if !text.contains(&prefix) {
+            return None;
+        }
+
+        Some(self.raws.iter().enumerate().fold(text.to_string(), |text, (i, raw)| {
+            text.replace(&format!("{prefix}{i}{RAW_END}"), raw)
+        }))
+    }
+
+    // Included files must be allowed by the policy, then are found by the resolver
+    fn include(&self, file: &str) -> Result<Option<(String, String)>, Error> {
+        let rejected = |reason: &str| Error::IncludeRejected(file.to_string(), reason.to_string());
+
+        if !self.env.include_policy.enabled {
+            return Err(rejected("includes are disabled"));
+        }
+        if !self.env.include_policy.allows(file) {
+            return Err(rejected("extension not allowed"));
+        }
+        let resolver = self.env.resolver.as_deref().unwrap_or(&self.env.files);
+
+        resolver.resolve(file, self.includes.last().map(|name| name.as_str()))
+    }
+
+    // Local variables hide assigned ones of the same name until the scope is left
+    fn enter(&mut self, scope: HashMap<String, Value>) -> Vec<(String, Value)> {
+        let hidden = scope.keys().filter_map(|name| self.lets.remove_entry(name)).collect();
+
+        self.scopes.push(scope);
+
+        hidden
+    }
+
+    fn leave(&mut self, hidden: Vec<(String, Value)>) {
+        self.scopes.pop();
+        self.lets.extend(hidden);
+    }
+
+    // Change a variable of the innermost scope
+    fn bind(&mut self, name: &str, value: Value) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), value);
+        }
+    }
+}
+
+/// Anything which can be supplied as a template variable
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+impl<T: AsRef<str> + ?Sized> ToValue for T {
+    fn to_value(&self) -> Value {
+        Value::Str(self.as_ref().to_string())
+    }
+}
+
+impl ToValue for Value {
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+}
 
 #[derive(Debug)]
 /// Class to hold hidden data about template
@@ -45,10 +720,15 @@ impl <'a> Template<'a> {
     /// use stemplate::Template;
     /// Template::new_delimit("My name is {%name%}", "{%", "%}");
     pub fn new_delimit(expanded: &'a str, sdlim: &'a str, edlim: &'a str) -> Self {
+        Template::parse(expanded.trim(), sdlim, edlim)
+    }
+
+    // Parse without trimming, block bodies must keep their whitespace
+    fn parse(expanded: &'a str, sdlim: &'a str, edlim: &'a str) -> Self {
         fn find_end(s: &str, sdlim: &str, edlim: &str) -> Option<usize> {
             let mut level = 0;
 
-            for (i, c) in s.chars().enumerate() {
+            for (i, c) in s.char_indices() {
                 if sdlim.starts_with(c) && s[i..].starts_with(sdlim) {
                     level += 1;
                 } else if edlim.starts_with(c) && s[i..].starts_with(edlim) {
@@ -62,7 +742,6 @@ impl <'a> Template<'a> {
             None
         }
 
-        let expanded = expanded.trim();
         let mut template = Self { replaces: Vec::new(), expanded, sdlim, edlim };
 
         if expanded.is_empty() {
@@ -77,8 +756,14 @@ impl <'a> Template<'a> {
         while cursor <= expanded.len() {
             if let Some(start) = expanded[cursor..].find(sdlim) {
                 let start = start + cursor;
-                if let Some(end) = find_end(&expanded[start..], sdlim, edlim) {
-                    let end = end + start;
+                let inner = start + sdlim.len();
+                // Comments end at the first --<end delimiter>, whatever they contain
+                let end = if expanded[inner..].starts_with("--") {
+                    expanded[inner + 2..].find(&format!("--{edlim}")).map(|end| end + inner + 4)
+                } else {
+                    find_end(&expanded[start..], sdlim, edlim).map(|end| end + start)
+                };
+                if let Some(end) = end {
                     replaces.push((
                         // The extracted key
                         &expanded[(start + sdlim.len())..end],
@@ -128,8 +813,51 @@ impl <'a> Template<'a> {
     /// let s = Template::new("${*|pets}").render(&args);
     /// assert_eq!(s, "woofers and kitty|rex and moggi");
     /// ```
-    pub fn render<V: AsRef<str> + std::fmt::Debug + std::string::ToString>(&self, vars: &HashMap<&str, V>) -> String {
-        self.recursive_render(vars, 0)
+    /// # Example
+    /// ```
+    /// // Strategy for combining lists of different lengths after '@',
+    /// // shortest (default), longest, strict or product
+    /// use std::collections::HashMap;
+    /// use stemplate::Template;
+    /// let mut args = HashMap::new();
+    /// args.insert("dog", "woofers|rex|freddy");
+    /// args.insert("cat", "kitty|moggi");
+    /// args.insert("pets", "${dog} and ${cat:-nobody}");
+    /// let s = Template::new("${*|pets@longest}").render(&args);
+    /// assert_eq!(s, "woofers and kitty|rex and moggi|freddy and nobody");
+    /// ```
+    pub fn render<V: ToValue>(&self, vars: &HashMap<&str, V>) -> String {
+        self.try_render(vars).unwrap_or_default()
+    }
+
+    /// Render a template, reporting errors rather than rendering nothing.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use stemplate::{Error, Template};
+    /// let mut args = HashMap::new();
+    /// args.insert("dog", "woofers|rex|freddy");
+    /// args.insert("cat", "kitty|moggi");
+    /// args.insert("pets", "${dog} and ${cat}");
+    /// let e = Template::new("${*pets@strict}").try_render(&args);
+    /// assert_eq!(e, Err(Error::LengthMismatch(vec![("dog".into(), 3), ("cat".into(), 2)])));
+    /// ```
+    pub fn try_render<V: ToValue>(&self, vars: &HashMap<&str, V>) -> Result<String, Error> {
+        self.try_render_with(&Environment::default(), vars)
+    }
+
+    /// Render a template using an environment, for custom filters etc.
+    pub fn render_with<V: ToValue>(&self, env: &Environment, vars: &HashMap<&str, V>) -> String {
+        self.try_render_with(env, vars).unwrap_or_default()
+    }
+
+    /// Render a template using an environment, reporting errors.
+    pub fn try_render_with<V: ToValue>(&self, env: &Environment, vars: &HashMap<&str, V>) -> Result<String, Error> {
+        let vars: HashMap<&str, Value> = vars.iter()
+            .map(|(k, v)| (*k, v.to_value()))
+            .collect();
+
+        self.render_vars(&vars, env)
     }
 
     /// Render a template with string values. Convenience for use with serde hash maps.
@@ -156,11 +884,11 @@ impl <'a> Template<'a> {
     /// assert_eq!(s, "Doris Bloggs");
     /// ```
     pub fn render_strings(&self, vars: &HashMap<String, String>) -> String {
-        let vars: HashMap<&str, &str> = vars.iter()
-            .map(|(k, v)| (k.as_str(), v.as_str()))
+        let vars: HashMap<&str, Value> = vars.iter()
+            .map(|(k, v)| (k.as_str(), v.to_value()))
             .collect();
         
-        self.recursive_render(&vars, 0)
+        self.render_vars(&vars, &Environment::default()).unwrap_or_default()
     }
 
     /// Render a template from environment variables.
@@ -177,18 +905,25 @@ impl <'a> Template<'a> {
     /// //assert_eq!(s, "File contains: inc");
     /// ```
     pub fn render_env(&self) -> String {
-        let vars: HashMap<&str, String> = HashMap::new();
+        let vars: HashMap<&str, Value> = HashMap::new();
 
-        self.recursive_render(&vars, 0)
+        self.render_vars(&vars, &Environment::default()).unwrap_or_default()
     }
 
-    fn recursive_render<V: AsRef<str> + std::fmt::Debug + std::string::ToString>(&self, vars: &HashMap<&str, V>, level: u8) -> String {
+    fn render_vars(&self, vars: &HashMap<&str, Value>, env: &Environment) -> Result<String, Error> {
+        let mut state = State::new(env);
+        let output = self.recursive_render(vars, &mut state, 0)?;
 
-        fn default<V: AsRef<str> + std::fmt::Debug + std::string::ToString>(key: &str, delimiter: &str, vars: &HashMap<&str, V>) -> String {
+        Ok(state.unstash(&output).unwrap_or(output))
+    }
+
+    fn recursive_render(&self, vars: &HashMap<&str, Value>, state: &mut State, level: u8) -> Result<String, Error> {
+
+        fn default(key: &str, delimiter: &str, vars: &HashMap<&str, Value>, state: &State) -> String {
             let bits: Vec<_> = key.split(delimiter).collect();
 
-            match vars.get(bits[0]) {
-                Some(v) if !v.as_ref().is_empty() =>
+            match lookup(vars, state, bits[0]) {
+                Some(v) if !v.to_string().is_empty() =>
                    v.to_string(),
                 _ => {
                    match std::env::var(bits[0]) {
@@ -199,12 +934,12 @@ impl <'a> Template<'a> {
             }
         }
 
-        fn other_sources<V: AsRef<str> + std::fmt::Debug + std::string::ToString>(key: &str, vars: &HashMap<&str, V>) -> String {
+        fn other_sources(key: &str, vars: &HashMap<&str, Value>, state: &State) -> String {
             // Implement default values if provided
             if key.contains(":-") {
-                default(key, ":-", vars)
+                default(key, ":-", vars, state)
             } else if key.contains(":=") {
-                default(key, ":=", vars)
+                default(key, ":=", vars, state)
             // Okay, try environment then
             } else {
                 match std::env::var(key) {
@@ -214,58 +949,229 @@ impl <'a> Template<'a> {
             }
         }
 
+        fn resolve(key: &str, vars: &HashMap<&str, Value>, state: &State) -> String {
+            match lookup(vars, state, key) {
+                Some(v) => v.to_string(),
+                None if key == "now" => filters::date::iso(state.env.now()),
+                None => other_sources(key, vars, state)
+            }
+        }
+
         let replaces = &self.replaces;
         let expanded = &self.expanded;
         let mut output = String::new();
         let mut cursor: usize = 0;
 
         // Only used for Multi-values
-        let mut mvv: HashMap<&str, Vec<String>> = HashMap::new();
-        let mut vars2: HashMap<&str, String> = HashMap::new();
-        let mut vc: HashMap<&str, usize> = HashMap::new();
+        let mut mvv: HashMap<String, Vec<String>> = HashMap::new();
         let mut is_single = true;
         let mut is_literal = false;
+        // Blocks are collected for the parent, which replaces this template's output
+        let parent = replaces.iter().find_map(|(key, _)| extends(key));
+
+        let mut i = 0;
+
+        while i < replaces.len() {
+            let (key, (start, end)) = &replaces[i];
 
-        for (key, (start, end)) in replaces.iter() {
             output.push_str(&expanded[cursor..*start]);
-            // Read from file?
-            if key.starts_with('!') && key.ends_with(".inc") {
-                match std::fs::read_to_string(&key[1..]) {
-                    Ok(content) => {
-                        let mut content = content.trim().to_string();
+            // Explicit loop, body repeated for each value or record entry
+            if let Some((name, value_name, list)) = for_loop(key) {
+                if let Some((alt, fin)) = self.find_block(i) {
+                    let mark = output.len();
+                    let body_end = replaces[alt.unwrap_or(fin)].1.0;
+                    let list = lookup(vars, state, list);
+                    let is_map = matches!(list, Some(Value::Map(_)));
+                    let items: Vec<(Value, Value)> = match list {
+                        Some(Value::Map(m)) => m.iter()
+                            .map(|(k, v)| (Value::from(k.as_str()), v.clone()))
+                            .collect(),
+                        Some(v) => v.items().into_iter()
+                            .enumerate()
+                            .map(|(n, v)| (Value::from(n.to_string()), v))
+                            .collect(),
+                        None => Vec::new()
+                    };
 
-                        if content.contains(self.sdlim) {
-                            content = Template::new_delimit(&content, self.sdlim, self.edlim).recursive_render(vars, level + 1);
+                    if items.is_empty() {
+                        if let Some(alt) = alt {
+                            let body = &expanded[replaces[alt].1.1..replaces[fin].1.0];
+
+                            output.push_str(&Template::parse(body, self.sdlim, self.edlim).recursive_render(vars, state, level + 1)?);
+                        }
+                    } else {
+                        let body = Template::parse(&expanded[*end..body_end], self.sdlim, self.edlim);
+                        let length = items.len();
+                        let scope = [name, value_name.unwrap_or(name), "loop"].iter()
+                            .map(|name| (name.to_string(), Value::from("")))
+                            .collect();
+                        let hidden = state.enter(scope);
+
+                        for (n, (k, v)) in items.into_iter().enumerate() {
+                            match value_name {
+                                Some(value_name) => {
+                                    state.bind(name, k);
+                                    state.bind(value_name, v);
+                                },
+                                None if is_map => state.bind(name, k),
+                                None => state.bind(name, v)
+                            }
+                            let mut lv = BTreeMap::new();
+                            lv.insert("index".to_string(), Value::from((n + 1).to_string()));
+                            lv.insert("index0".to_string(), Value::from(n.to_string()));
+                            lv.insert("first".to_string(), Value::from((n == 0).to_string()));
+                            lv.insert("last".to_string(), Value::from((n == length - 1).to_string()));
+                            lv.insert("length".to_string(), Value::from(length.to_string()));
+                            state.bind("loop", Value::Map(lv));
+
+                            output.push_str(&body.recursive_render(vars, state, level + 1)?);
                         }
+                        state.leave(hidden);
+                    }
+                    if state.env.indent {
+                        align(&mut output, mark);
+                    }
 
-                        output.push_str(content.trim().as_ref())
-                    },
-                    Err(_) => output.push_str("".as_ref())
+                    cursor = replaces[fin].1.1;
+                    i = fin + 1;
+                    continue;
                 }
-            // Exists with value test
-            } else if key.starts_with('?') && key.contains('=') {
-               let mut value: String = "".to_string();
-               let mut vd: Vec<&str> = key.split(":-").collect();
+            }
+            // Named block, overridden by templates extending this one
+            if let Some(name) = block(key) {
+                if let Some((_, fin)) = self.find_block(i) {
+                    let body = &expanded[*end..replaces[fin].1.0];
 
-               if vd.len() != 2 {
-                   vd = key.split(":=").collect();
-               }
-               if vd.len() == 2 {
-                   let lhs = &(vd[0])[1..];
-                   let vv: Vec<&str> = lhs.split('=').collect();
+                    if parent.is_some() {
+                        state.blocks.entry(name.to_string()).or_default().push(body.to_string());
+                    } else {
+                        let mut bodies = state.blocks.get(name).cloned().unwrap_or_default();
+                        bodies.push(body.to_string());
+                        let mark = output.len();
 
-                   if vv.len() == 2 {
-                       if let Some(v) = vars.get(vv[0]) {
-                           if v.to_string() == vv[1] {
-                               value = vd[1].trim().to_string();
-                           }
-                       }
-                   }
-                   output.push_str(value.as_ref())
-               }
-            // Multi Value substitution
-            } else if let Some(mut key) = key.strip_prefix('*') {
-                is_single = false;
+                        output.push_str(&self.render_chain(bodies, vars, state, level)?);
+                        if state.env.indent {
+                            align(&mut output, mark);
+                        }
+                    }
+
+                    cursor = replaces[fin].1.1;
+                    i = fin + 1;
+                    continue;
+                }
+            }
+            // Macro definition, body is rendered when called
+            if let Some((name, params)) = macro_def(key) {
+                if let Some((_, fin)) = self.find_block(i) {
+                    let body = expanded[*end..replaces[fin].1.0].to_string();
+                    let params = split_args(params).iter().map(|p| p.to_string()).collect();
+
+                    state.macros.insert(name.to_string(), (params, body));
+
+                    cursor = replaces[fin].1.1;
+                    i = fin + 1;
+                    continue;
+                }
+            }
+            // Filters apply to whatever the key renders, after defaults
+            let (key, filters) = if assignment(key).is_some() {
+                (*key, Vec::new())
+            } else {
+                self.pipeline(key, state.env)
+            };
+            let mark = output.len();
+            // Substituted variables, as opposed to template text, are escaped
+            let mut is_value = false;
+            // Raw includes are filtered, then kept as they are
+            let mut is_raw = false;
+
+            // Comment, nothing is output
+            if key.starts_with("--") || extends(key).is_some() {
+            // Content of the block being overridden
+            } else if key.trim() == "super" && !state.supers.is_empty() {
+                let bodies = state.supers.last().cloned().unwrap_or_default();
+
+                output.push_str(&self.render_chain(bodies, vars, state, level)?);
+            // Assignment, quoted values are rendered now, otherwise a variable is copied
+            } else if let Some((name, value)) = assignment(key) {
+                let value = self.evaluate(value, vars, state, level)?;
+
+                state.lets.insert(name.to_string(), value);
+            // Macro call, arguments are only visible in the macro body
+            } else if let Some((name, args)) = call(key) {
+                let (params, body) = state.macros.get(name)
+                    .cloned()
+                    .ok_or_else(|| Error::UnknownMacro(name.to_string()))?;
+                if state.calls.iter().any(|call| call == name) {
+                    let mut chain = state.calls.clone();
+                    chain.push(name.to_string());
+
+                    return Err(Error::MacroRecursion(chain));
+                }
+                let args = split_args(args);
+                let mut scope = HashMap::new();
+
+                for (n, param) in params.iter().enumerate() {
+                    let value = match args.get(n) {
+                        Some(arg) => self.evaluate(arg, vars, state, level)?,
+                        None => Value::from("")
+                    };
+
+                    scope.insert(param.to_string(), value);
+                }
+                let hidden = state.enter(scope);
+                state.calls.push(name.to_string());
+
+                output.push_str(&Template::parse(&body, self.sdlim, self.edlim).recursive_render(vars, state, level + 1)?);
+                state.calls.pop();
+                state.leave(hidden);
+            // Read from file?
+            } else if let Some((file, raw, args)) = inclusion(key) {
+                if let Some((path, content)) = state.include(file)? {
+                    if raw {
+                        output.push_str(content.trim());
+                        is_raw = true;
+                    } else if args.is_empty() {
+                        output.push_str(&self.render_include(path, &content, vars, state, level)?);
+                    } else {
+                        // Arguments are variables of the include only
+                        let mut scope = HashMap::new();
+
+                        for (name, value) in &args {
+                            let value = self.evaluate(value, vars, state, level)?;
+
+                            scope.insert(name.to_string(), value);
+                        }
+                        let hidden = state.enter(scope);
+
+                        output.push_str(&self.render_include(path, &content, vars, state, level)?);
+                        state.leave(hidden);
+                    }
+                }
+            // Exists with value test
+            } else if key.starts_with('?') && key.contains('=') {
+               let mut value: String = "".to_string();
+               let mut vd: Vec<&str> = key.split(":-").collect();
+
+               if vd.len() != 2 {
+                   vd = key.split(":=").collect();
+               }
+               if vd.len() == 2 {
+                   let lhs = &(vd[0])[1..];
+                   let vv: Vec<&str> = lhs.split('=').collect();
+
+                   if vv.len() == 2 {
+                       if let Some(v) = lookup(vars, state, vv[0]) {
+                           if v.to_string() == vv[1] {
+                               value = vd[1].trim().to_string();
+                           }
+                       }
+                   }
+                   output.push_str(value.as_ref())
+               }
+            // Multi Value substitution
+            } else if let Some(mut key) = key.strip_prefix('*') {
+                is_single = false;
                 let delim = if key.chars().next().unwrap().is_alphabetic() {
                     "\n"
                 } else {
@@ -274,76 +1180,149 @@ impl <'a> Template<'a> {
 
                     delim
                 };
-                if let Some(key) = vars.get(key) {
+                let (key, zip) = strategy(key);
+                if let Some(key) = lookup(vars, state, key) {
                     let key = key.to_string();
+                    // Escaped as a whole below, not value by value
+                    is_value = true;
+                    let saved = std::mem::replace(&mut state.escape, Escape::None);
 
                     if mvv.is_empty() { // We only need to do this once
-                        vars2 = vars.iter()
-                            .map(|(k,v)| (*k, v.to_string()))
-                            .collect();
-                        for (k, v) in vars2.iter() {
-                            let v = v.to_string();
+                        let names = vars.keys().map(|k| k.to_string())
+                            .chain(state.scopes.iter().flat_map(|scope| scope.keys().cloned()));
+
+                        for k in names {
+                            let v = lookup(vars, state, &k).map(|v| v.to_string()).unwrap_or_default();
                             if v.contains('|') {
                                 let val = v.split('|').map(|i| i.trim().into()).collect();
                                 mvv.insert(k, val);
                             }
                         }
                     }
-                    let mi = mvv.iter()
-                        .filter(|(k,_)| key.contains(&format!("{}{k}{}", self.sdlim, self.edlim)))
-                        .map(|(_,v)| v.len())
-                        .min();
-                    if let Some(mi) = mi {
-                        for i in 0 .. mi {
-                            mvv.iter()
-                                .filter(|(k,v)| mi <= v.len() && key.contains(&format!("{}{k}{}", self.sdlim, self.edlim)))
-                                .for_each(|(k,v)| { vars2.insert(k, v[i].clone()); });
-                            let mut content = Template::new_delimit(&key, self.sdlim, self.edlim).recursive_render(&vars2, level + 1) + delim;
-
-                            if i == mi - 1 {
-                                content = content[..content.len()-1].to_string();
-                            }
-
-                            output.push_str(content.as_ref())
-                        }
+                    let mut refs: Vec<(usize, &str, &Vec<String>)> = mvv.iter()
+                        .filter_map(|(k, v)| self.position(&key, k, zip != Zip::Shortest).map(|p| (p, k.as_str(), v)))
+                        .collect();
+                    refs.sort();
+                    let lens: Vec<(&str, usize)> = refs.iter().map(|(_, k, v)| (*k, v.len())).collect();
+                    let template = Template::new_delimit(&key, self.sdlim, self.edlim);
+                    let mut contents: Vec<String> = Vec::new();
+
+                    if lens.is_empty() {
+                        contents.push(template.recursive_render(vars, state, level + 1)?);
                     } else {
-                            let mut content = Template::new_delimit(&key, self.sdlim, self.edlim).recursive_render(&vars2, level + 1) + delim;
-                            content = content[..content.len()-1].to_string();
+                        // Each instance sees one value of each list
+                        let scope = lens.iter().map(|(k, _)| (k.to_string(), Value::from(""))).collect();
+                        let hidden = state.enter(scope);
 
-                            output.push_str(content.as_ref())
+                        for row in zip.rows(&lens)? {
+                            for ((_, k, v), i) in refs.iter().zip(row) {
+                                let v = i.map(|i| v[i].as_str()).unwrap_or("");
+
+                                state.bind(k, Value::from(v));
+                            }
+                            contents.push(template.recursive_render(vars, state, level + 1)?);
+                        }
+                        state.leave(hidden);
                     }
+                    state.escape = saved;
+
+                    output.push_str(&contents.join(delim))
                 }
             } else if let Some(key) = key.strip_prefix('=') {
-                if let Some(content) = vars.get(key) {
+                if let Some(content) = lookup(vars, state, key) {
                     is_literal = true;
-                    output.push_str(content.as_ref())
+                    output.push_str(&content.to_string())
                 }
+            // Cycle through values, reset with =<start> or pick one with @<index>
             } else if let Some(key) = key.strip_prefix('#') {
-                if let Some(v) = vars.get(key) {
-                    let v = v.to_string();
-                    let vs: Vec<&str> = v.split('|').collect();
-                    let _ = vc.entry(key)
-                        .and_modify(|v| { *v = (*v + 1) % vs.len(); })
-                        .or_insert(0);
-                    let i = vc.get(key).unwrap();
-                    output.push_str(vs[*i])
+                is_value = true;
+                if let Some((key, start)) = key.split_once('=') {
+                    state.counters.insert(key.to_string(), start.trim().parse().unwrap_or(0));
+                } else {
+                    let (key, index) = match key.rsplit_once('@').and_then(|(name, i)| Some((name, i.parse::<usize>().ok()?))) {
+                        Some((name, i)) => (name, Some(i)),
+                        None => (key, None)
+                    };
+
+                    if let Some(v) = lookup(vars, state, key) {
+                        let v = v.to_string();
+                        let vs: Vec<&str> = v.split('|').collect();
+                        let i = match index {
+                            Some(i) => i,
+                            None => {
+                                let next = state.counters.entry(key.to_string()).or_insert(0);
+                                *next += 1;
+                                *next - 1
+                            }
+                        };
+                        output.push_str(vs[i % vs.len()])
+                    }
                 }
             } else {
-                let v = 
-                    match vars.get(key) {
-                        Some(v) => v.to_string(),
-                        None => other_sources(key, vars)
-                    };
+                // Empty when only marking the end of the template
+                is_value = !key.is_empty();
+                let v = match coalesce(key) {
+                    // The first alternative which is not empty
+                    Some(alternatives) => alternatives.iter()
+                        .map(|a| match a.strip_prefix('"').and_then(|a| a.strip_suffix('"')) {
+                            Some(literal) => literal.to_string(),
+                            None => resolve(a, vars, state)
+                        })
+                        .find(|v| !v.trim().is_empty())
+                        .unwrap_or_default(),
+                    None => resolve(key, vars, state)
+                };
 
                 if is_single || !v.contains('|') {
                     output.push_str(v.trim().as_ref())
                 }
             }
+            let escape = is_value && state.escape != Escape::None && !filters.iter().any(|(name, _)| filters::is_escaper(name));
+            // Whatever holds raw content stays raw once filtered
+            let mut protect = is_raw;
+            if !filters.is_empty() || escape {
+                let mut value = output.split_off(mark);
+
+                if !is_raw && !key.starts_with('=') && value.contains(self.sdlim) {
+                    // A value is escaped once, including any variables it contains
+                    let saved = state.escape;
+                    if is_value {
+                        state.escape = Escape::None;
+                    }
+                    value = Template::new_delimit(&value, self.sdlim, self.edlim).recursive_render(vars, state, level + 1)?;
+                    state.escape = saved;
+                }
+                if let Some(unstashed) = state.unstash(&value) {
+                    value = unstashed;
+                    protect = true;
+                }
+                value = self.filter(value, &filters, state)?;
+                if escape {
+                    value = state.escape.apply(&value);
+                }
+                output.push_str(&value);
+            }
+            if state.env.indent && !filters.iter().any(|(name, _)| *name == "indent") {
+                align(&mut output, mark);
+            }
+            if protect {
+                let value = output.split_off(mark);
+
+                output.push_str(&state.stash(value));
+            }
             cursor = *end;
+            i += 1;
+        }
+
+        if let Some(parent) = parent {
+            return match state.include(parent)? {
+                Some((path, content)) => self.render_include(path, &content, vars, state, level),
+                None => Ok(String::new())
+            };
         }
 
         if !is_literal && level < 16 && output.contains(self.sdlim) {
-            output = Template::new_delimit(&output, self.sdlim, self.edlim).recursive_render(vars, level + 1);
+            output = Template::new_delimit(&output, self.sdlim, self.edlim).recursive_render(vars, state, level + 1)?;
         }
 
         // If there's more text after the `${}`
@@ -351,7 +1330,389 @@ impl <'a> Template<'a> {
             output.push_str(&expanded[cursor..]);
         }
 
-        output
+        Ok(output)
+    }
+
+    // Includes within an included file are found relative to it first
+    fn render_include(&self, name: String, content: &str, vars: &HashMap<&str, Value>, state: &mut State, level: u8) -> Result<String, Error> {
+        let content = content.trim();
+
+        if !content.contains(self.sdlim) {
+            return Ok(content.to_string());
+        }
+        if state.includes.contains(&name) {
+            let mut chain = state.includes.clone();
+            chain.push(name);
+
+            return Err(Error::IncludeCycle(chain));
+        }
+        state.includes.push(name);
+        let content = Template::new_delimit(content, self.sdlim, self.edlim).recursive_render(vars, state, level + 1);
+        state.includes.pop();
+
+        Ok(content?.trim().to_string())
+    }
+
+    // Render the first body, with the rest available to ${super}
+    fn render_chain(&self, bodies: Vec<String>, vars: &HashMap<&str, Value>, state: &mut State, level: u8) -> Result<String, Error> {
+        match bodies.split_first() {
+            Some((body, rest)) => {
+                state.supers.push(rest.to_vec());
+                let content = Template::parse(body, self.sdlim, self.edlim).recursive_render(vars, state, level + 1);
+                state.supers.pop();
+
+                content
+            },
+            None => Ok(String::new())
+        }
+    }
+
+    // Quoted values are rendered, otherwise they name a variable. Either may be filtered.
+    fn evaluate(&self, value: &str, vars: &HashMap<&str, Value>, state: &mut State, level: u8) -> Result<Value, Error> {
+        let (value, filters) = self.pipeline(value, state.env);
+        let value = match value.trim().strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(value) => {
+                // Escaped when substituted, not now
+                let saved = std::mem::replace(&mut state.escape, Escape::None);
+                let value = Template::parse(value, self.sdlim, self.edlim).recursive_render(vars, state, level + 1);
+                state.escape = saved;
+
+                Value::from(value?)
+            },
+            None => lookup(vars, state, value.trim()).cloned().unwrap_or_else(|| Value::from(""))
+        };
+
+        if filters.is_empty() {
+            Ok(value)
+        } else if let Some(value) = state.unstash(&value.to_string()) {
+            let value = self.filter(value, &filters, state)?;
+
+            Ok(Value::from(state.stash(value)))
+        } else {
+            Ok(Value::from(self.filter(value.to_string(), &filters, state)?))
+        }
+    }
+
+    // Split "<key> | <filter> | <filter>(<args>)", a '|' in quotes, brackets, nested
+    // variables or a :- default does not count, nor does one without spaces around
+    // it unless a filter name follows. If any part is not a filter there is no pipeline.
+    fn pipeline<'k>(&self, key: &'k str, env: &Environment) -> (&'k str, Vec<(&'k str, Vec<&'k str>)>) {
+        let mut bits = Vec::new();
+        let (mut quoted, mut brackets, mut nested) = (false, 0, 0);
+        // Skip the '*' modifier's delimiter, which may be a '|'
+        let mut from = match key.strip_prefix('*').and_then(|k| k.chars().next()) {
+            Some(c) if !c.is_alphabetic() => 1 + c.len_utf8(),
+            _ => 0
+        };
+        let head = from;
+
+        let is_filter = |name: &str| filters::builtin(name).is_some() || env.filters.contains_key(name);
+
+        for (i, c) in key.char_indices().skip_while(|(i, _)| *i < head) {
+            if key[i..].starts_with(self.sdlim) {
+                nested += 1;
+            } else if key[i..].starts_with(self.edlim) && nested > 0 {
+                nested -= 1;
+            }
+            let top = !quoted && brackets == 0 && nested == 0;
+
+            match c {
+                '"' => quoted = !quoted,
+                '(' if !quoted => brackets += 1,
+                ')' if !quoted && brackets > 0 => brackets -= 1,
+                // The default is the rest of the key
+                ':' if top && (key[i + 1..].starts_with('-') || key[i + 1..].starts_with('=')) => break,
+                '|' if top => {
+                    let after = &key[i + 1..];
+                    let spaced = key[..i].ends_with(char::is_whitespace) && after.starts_with(char::is_whitespace);
+                    let name = after.split(|c: char| !(c.is_alphanumeric() || c == '_')).next().unwrap_or("");
+
+                    if spaced || is_filter(name) {
+                        bits.push(&key[from..i]);
+                        from = i + 1;
+                    }
+                },
+                _ => ()
+            }
+        }
+        if bits.is_empty() {
+            return (key, Vec::new());
+        }
+        bits.push(&key[from..]);
+
+        let mut filters = Vec::new();
+
+        for bit in &bits[1..] {
+            let bit = bit.trim();
+
+            match invocation(bit) {
+                Some((name, args)) => filters.push((name, split_args(args))),
+                None if is_ident(bit) => filters.push((bit, Vec::new())),
+                None => return (key, Vec::new())
+            }
+        }
+
+        (key[..head + bits[0].len()].trim_end(), filters)
+    }
+
+    // Apply filters in turn, custom before built in. Arguments may be quoted.
+    fn filter(&self, value: String, filters: &[(&str, Vec<&str>)], state: &State) -> Result<String, Error> {
+        let mut value = value;
+
+        for (name, args) in filters {
+            let args: Vec<&str> = args.iter()
+                .map(|a| a.strip_prefix('"').and_then(|a| a.strip_suffix('"')).unwrap_or(a))
+                .collect();
+            let result = match (state.env.filters.get(*name), filters::builtin(name)) {
+                (Some(filter), _) => filter(&value, &args),
+                (None, Some(filter)) => filter(&value, &args),
+                (None, None) => return Err(Error::UnknownFilter(name.to_string()))
+            };
+
+            value = result.map_err(|e| Error::Filter(name.to_string(), e))?;
+        }
+
+        Ok(value)
+    }
+
+    // Where name is first referenced in content, optionally including with a default
+    fn position(&self, content: &str, name: &str, defaults: bool) -> Option<usize> {
+        let ends: &[&str] = if defaults { &[self.edlim, ":-", ":="] } else { &[self.edlim] };
+
+        ends.iter()
+            .filter_map(|end| content.find(&format!("{}{name}{end}", self.sdlim)))
+            .min()
+    }
+
+    // Find the matching ${end} (and optional ${else}) for the block opened at replaces[open]
+    fn find_block(&self, open: usize) -> Option<(Option<usize>, usize)> {
+        let mut depth = 0;
+        let mut alt = None;
+
+        for (i, (key, _)) in self.replaces.iter().enumerate().skip(open + 1) {
+            match key.trim() {
+                "end" if depth == 0 => return Some((alt, i)),
+                "end" => depth -= 1,
+                "else" if depth == 0 && alt.is_none() => alt = Some(i),
+                key if is_block_start(key) => depth += 1,
+                _ => ()
+            }
+        }
+
+        None
+    }
+}
+
+// Following lines of a multi-line value, loop or block from mark line up with it
+fn align(output: &mut String, mark: usize) {
+    if !output[mark..].contains('\n') {
+        return;
+    }
+    let line = output[..mark].rfind('\n').map(|n| n + 1).unwrap_or(0);
+    let indent: String = output[line..mark].chars()
+        .map(|c| if c.is_whitespace() { c } else { ' ' })
+        .collect();
+    let value = output.split_off(mark);
+
+    output.push_str(&filters::indent_lines(&value, &indent));
+}
+
+// Keys which open a block closed by ${end}
+fn is_block_start(key: &str) -> bool {
+    for_loop(key).is_some() || macro_def(key).is_some() || block(key).is_some()
+}
+
+// Parse "<name>@<strategy>", only from the name and not any default after it.
+// Shortest, the default, ignores lists referenced with a default, as fillers.
+fn strategy(key: &str) -> (&str, Zip) {
+    let name = key.split(':').next().unwrap_or(key);
+
+    name.rsplit_once('@')
+        .and_then(|(name, zip)| Some((name, Zip::parse(zip)?)))
+        .unwrap_or((key, Zip::Shortest))
+}
+
+// Parse "block <name>"
+fn block(key: &str) -> Option<&str> {
+    let name = key.trim().strip_prefix("block ")?.trim();
+
+    is_ident(name).then_some(name)
+}
+
+// Parse "extends <file>"
+fn extends(key: &str) -> Option<&str> {
+    let file = key.trim().strip_prefix("extends ")?.trim();
+
+    (!file.is_empty()).then_some(file)
+}
+
+// Include arguments, (name, value)
+type Args<'k> = Vec<(&'k str, &'k str)>;
+
+// Parse "!<file> <name>=<value> ...", values are quoted or variable names,
+// or "!=<file>" which is included as is
+fn inclusion(key: &str) -> Option<(&str, bool, Args<'_>)> {
+    let key = key.strip_prefix('!')?;
+    let (key, raw) = match key.strip_prefix('=') {
+        Some(key) => (key.trim(), true),
+        None => (key.trim(), false)
+    };
+    let (file, rest) = key.split_once(char::is_whitespace).unwrap_or((key, ""));
+    let mut args = Vec::new();
+    let mut quoted = false;
+    let mut from = 0;
+
+    for (i, c) in rest.char_indices().chain([(rest.len(), ' ')]) {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                let arg = &rest[from..i];
+
+                if !arg.is_empty() {
+                    let (name, value) = arg.split_once('=')?;
+
+                    if !is_ident(name) {
+                        return None;
+                    }
+                    args.push((name, value));
+                }
+                from = i + c.len_utf8();
+            },
+            _ => ()
+        }
+    }
+
+    Some((file, raw, args))
+}
+
+// Parse "macro <name>(<params>)"
+fn macro_def(key: &str) -> Option<(&str, &str)> {
+    invocation(key.trim().strip_prefix("macro ")?)
+}
+
+// Parse "call <name>(<args>)"
+fn call(key: &str) -> Option<(&str, &str)> {
+    invocation(key.trim().strip_prefix("call ")?)
+}
+
+// Parse "<name>(<args>)"
+fn invocation(s: &str) -> Option<(&str, &str)> {
+    let (name, args) = s.trim().strip_suffix(')')?.split_once('(')?;
+    let name = name.trim();
+
+    if is_ident(name) {
+        Some((name, args))
+    } else {
+        None
+    }
+}
+
+// Split on commas which are not in double quotes
+fn split_args(args: &str) -> Vec<&str> {
+    let mut bits = Vec::new();
+    let mut quoted = false;
+    let mut from = 0;
+
+    for (i, c) in args.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                bits.push(args[from..i].trim());
+                from = i + 1;
+            },
+            _ => ()
+        }
+    }
+    if !args[from..].trim().is_empty() || !bits.is_empty() {
+        bits.push(args[from..].trim());
+    }
+
+    bits
+}
+
+// Parse "for <name> in <list>" or "for <key>, <value> in <list>"
+fn for_loop(key: &str) -> Option<(&str, Option<&str>, &str)> {
+    let (names, list) = key.trim().strip_prefix("for ")?.split_once(" in ")?;
+    let (name, value_name) = match names.split_once(',') {
+        Some((name, value_name)) => (name.trim(), Some(value_name.trim())),
+        None => (names.trim(), None)
+    };
+    let list = list.trim();
+
+    if is_ident(name) && value_name.map(is_ident).unwrap_or(true) && !list.is_empty() {
+        Some((name, value_name, list))
+    } else {
+        None
+    }
+}
+
+// Parse "let <name> = <value>"
+fn assignment(key: &str) -> Option<(&str, &str)> {
+    let (name, value) = key.trim().strip_prefix("let ")?.split_once('=')?;
+    let name = name.trim();
+
+    if is_ident(name) {
+        Some((name, value.trim()))
+    } else {
+        None
+    }
+}
+
+// Look up a possibly dotted key, "row.name" is field name of record row
+// Loop variables and arguments hide variables assigned with ${let ...}, which
+// hide those supplied.
+fn lookup<'v>(vars: &'v HashMap<&str, Value>, state: &'v State, key: &str) -> Option<&'v Value> {
+    let value = state.scopes.iter().rev()
+        .find_map(|scope| scope.get(key))
+        .or_else(|| state.lets.get(key))
+        .or_else(|| vars.get(key));
+
+    match value {
+        Some(v) => Some(v),
+        None => {
+            let (head, rest) = key.split_once('.')?;
+
+            lookup(vars, state, head)?.get(rest)
+        }
+    }
+}
+
+// Split "a ?? b ?? \"literal\"" into its alternatives, None if there is only one.
+// A :- default is part of the last alternative, any "??" in it is text.
+fn coalesce(key: &str) -> Option<Vec<&str>> {
+    let mut alternatives = Vec::new();
+    let mut quoted = false;
+    let mut from = 0;
+
+    for (i, c) in key.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ':' if !quoted && (key[i + 1..].starts_with('-') || key[i + 1..].starts_with('=')) => break,
+            '?' if !quoted && i >= from && key[i..].starts_with("??") => {
+                alternatives.push(key[from..i].trim());
+                from = i + 2;
+            },
+            _ => ()
+        }
+    }
+    if alternatives.is_empty() {
+        return None;
+    }
+    alternatives.push(key[from..].trim());
+
+    Some(alternatives)
+}
+
+fn is_ident(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Multi-values are '|' separated, an empty value has none
+fn split_values(v: &str) -> Vec<String> {
+    if v.trim().is_empty() {
+        Vec::new()
+    } else {
+        v.split('|').map(|i| i.trim().into()).collect()
     }
 }
 
@@ -435,6 +1796,27 @@ mod tests {
         assert_eq!(s, "Hello, Charles. You remind me of another Harry.");
     }
 
+    #[test]
+    fn cycle_nested() {
+        let mut args = HashMap::new();
+        args.insert("class", "odd|even");
+        args.insert("rows", "a|b|c");
+
+        let s = Template::new("${for r in rows}<tr class=\"${#class}\">${r}</tr>${end}").render(&args);
+
+        assert_eq!(s, "<tr class=\"odd\">a</tr><tr class=\"even\">b</tr><tr class=\"odd\">c</tr>");
+    }
+
+    #[test]
+    fn cycle_reset() {
+        let mut args = HashMap::new();
+        args.insert("name", "a|b|c");
+
+        let s = Template::new("${#name}${#name}${#name=}${#name} ${#name=2}${#name}${#name} ${#name@1}${#name@4}${#name}").render(&args);
+
+        assert_eq!(s, "aba ca bbb");
+    }
+
     #[test]
     fn twice() {
         let test: &str = "${name}, why are you writing code at ${time} again?";
@@ -557,80 +1939,942 @@ mod tests {
     }
 
     #[test]
-    fn include() {
+    fn assign() {
         let mut args = HashMap::new();
-        args.insert("example", "text");
-        let s = Template::new("File contains: ${!test.inc}").render(&args);
+        args.insert("site", "Shop");
+        args.insert("page", "Home");
+        args.insert("pets", "rex|kitty");
 
-        assert_eq!(s, "File contains: inc text");
+        let s = Template::new("${let title = \"${site} - ${page}\"}<title>${title}</title><h1>${title}</h1>${let all = pets}${for p in all}${p}${end}").render(&args);
+
+        assert_eq!(s, "<title>Shop - Home</title><h1>Shop - Home</h1>rexkitty");
     }
 
     #[test]
-    fn dont_include() {
-        let s = Template::new("${!/etc/passwd}").render_env();
+    fn assign_scope() {
+        let mut args = HashMap::new();
+        args.insert("name", "Charles");
+        args.insert("rows", "a|b");
 
-        assert_eq!(s, "");
+        let s = Template::new("${name} ${for r in rows}${let last = r}${end}${let name = \"Harry\"}${name} ${last} ${missing:-none}").render(&args);
+
+        assert_eq!(s, "Charles Harry b none");
     }
 
     #[test]
-    fn many() {
+    fn macros() {
         let mut args = HashMap::new();
-        args.insert("dog", "woofers|rex");
-        args.insert("cat", "kitty|moggi|tiger");
-        args.insert("pets", "${dog} and ${cat}");
+        args.insert("name", "Charles");
+        args.insert("email_label", "E-mail");
 
-        let s = Template::new("${*pets}").render(&args);
+        let s = Template::new(r#"${macro field(name, label)}<label for="${name}">${label}</label><input name="${name}"/>
+${end}${call field("email", email_label)}${call field("phone", "Phone, ${name}")}${name}"#).render(&args);
 
-        assert_eq!(s, "woofers and kitty\nrex and moggi");
+        assert_eq!(s, r#"<label for="email">E-mail</label><input name="email"/>
+<label for="phone">Phone, Charles</label><input name="phone"/>
+Charles"#);
     }
 
     #[test]
-    fn many_delim() {
+    fn macro_scope() {
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let s = Template::new(r#"${let label = "outer"}${macro m(label)}[${label}]${end}${call m("inner")}${call m()} ${label}"#).try_render(&args);
+
+        assert_eq!(s, Ok("[inner][] outer".to_string()));
+
+        let s = Template::new("${call missing()}").try_render(&args);
+
+        assert_eq!(s, Err(Error::UnknownMacro("missing".into())));
+    }
+
+    #[test]
+    fn filter() {
         let mut args = HashMap::new();
-        args.insert("dog", "woofers|rex");
-        args.insert("cat", "kitty|moggi");
-        args.insert("pets", "${dog} and ${cat}");
+        args.insert("name", "  charles DARWIN ");
+        args.insert("pets", "rex|kitty|moggi");
+        args.insert("full", "${name} and ${pets | length}");
 
-        let s = Template::new("${*|pets}").render(&args);
+        let s = Template::new(r#"${name | trim | capitalize}|${name|upper} ${pets | join(", ") | replace("kitty", "tiger")} ${pets | length} ${full | lower}"#).render(&args);
 
-        assert_eq!(s, "woofers and kitty|rex and moggi");
+        assert_eq!(s, "Charles darwin|CHARLES DARWIN rex, tiger, moggi 3 charles darwin and 3");
     }
 
     #[test]
-    fn many_delim2() {
+    fn filter_default() {
         let mut args = HashMap::new();
-        args.insert("dog", "woofers|rex");
-        args.insert("cat", "kitty|moggi");
-        args.insert("rabbit", "cuddly");
-        args.insert("pets", "${dog}, ${cat} and ${rabbit}");
+        args.insert("name", "");
 
-        let s = Template::new("I love ${*;pets} a lot").render(&args);
+        let s = Template::new(r#"${name | default("fred") | upper} ${name | default("Nobody") | lower} ${let n = "${missing:-x}" | upper}${n}"#).render(&args);
 
-        assert_eq!(s, "I love woofers, kitty and cuddly;rex, moggi and cuddly a lot");
+        assert_eq!(s, "FRED nobody X");
     }
 
     #[test]
-    fn many_delim3() {
+    fn filter_list_default() {
         let mut args = HashMap::new();
-        args.insert("marg", "arg0");
-        args.insert("top", "${marg}");
+        args.insert("name", "fred");
 
-        let s = Template::new("[${*,top}]").render(&args);
+        let s = Template::new("Colours: ${colours:-red|green} ${colours:=red | green} ${none:-a|upper} ${name|upper} ${name|green}").render(&args);
 
-        assert_eq!(s, "[arg0]");
+        assert_eq!(s, "Colours: red|green red | green a|upper FRED ");
     }
 
     #[test]
-    fn many_delim4() {
+    fn filter_errors() {
         let mut args = HashMap::new();
-        args.insert("marg", "arg0");
-        args.insert("mand_args", r#""${marg}""#);
-        args.insert("func", r#"[${*,mand_args}]"#);
+        args.insert("name", "Charles");
 
-        let s = Template::new("${func}").render(&args);
-        //let s = Template::new(r#"[${*,mand_args}]"#).render(&args);
+        let s = Template::new("${name | shout}").try_render(&args);
 
-        assert_eq!(s, "[\"arg0\"]");
+        assert_eq!(s, Err(Error::UnknownFilter("shout".into())));
+
+        let s = Template::new("${name | replace(\"C\")}").try_render(&args);
+
+        assert_eq!(s, Err(Error::Filter("replace".into(), "missing argument 2".into())));
+    }
+
+    #[test]
+    fn filter_custom() {
+        let mut env = Environment::new();
+        env.register_filter("obfuscate", |v, _| Ok::<_, String>(v.chars().rev().collect()))
+            .register_filter("upper", |v, _| Ok::<_, String>(format!("<{v}>")))
+            .register_filter("even", |v, _| match v.parse::<u32>() {
+                Ok(n) if n % 2 == 0 => Ok(v.to_string()),
+                _ => Err(format!("{v} is odd"))
+            });
+        let mut args = HashMap::new();
+        args.insert("id", "1234");
+
+        let s = Template::new("${id | obfuscate | upper}").render_with(&env, &args);
+
+        assert_eq!(s, "<4321>");
+
+        let s = Template::new("${id | obfuscate | even}").try_render_with(&env, &args);
+
+        assert_eq!(s, Err(Error::Filter("even".into(), "4321 is odd".into())));
+
+        let s = Template::new("${id | currency}").try_render_with(&env, &args);
+
+        assert_eq!(s.unwrap_err().to_string(), "unknown filter: currency");
+    }
+
+    #[test]
+    fn autoescape() {
+        let mut env = Environment::new();
+        env.autoescape(Escape::Html);
+        let mut args = HashMap::new();
+        args.insert("name", "<b>Tom & \"Jerry\"</b>");
+        args.insert("nested", "<i>${name}</i>");
+        args.insert("pets", "<rex>|kitty");
+
+        let s = Template::new(r#"<p title="${name}">${=name} ${name | raw} ${name | upper}</p>${nested}${let t = "[${name}]"}${t}${for p in pets}<li>${p}</li>${end}${?pets=<rex>|kitty:-<hr/>}"#).render_with(&env, &args);
+
+        assert_eq!(s, r#"<p title="&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;"><b>Tom & "Jerry"</b> <b>Tom & "Jerry"</b> &lt;B&gt;TOM &amp; &quot;JERRY&quot;&lt;/B&gt;</p>&lt;i&gt;&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;&lt;/i&gt;[&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;]<li>&lt;rex&gt;</li><li>kitty</li><hr/>"#);
+    }
+
+    #[test]
+    fn escapers() {
+        let mut args = HashMap::new();
+        args.insert("q", "fish & chips/£5");
+        args.insert("text", "it's \"so\"\n\t\\ \u{1}");
+        args.insert("cell", "a, \"b\"");
+
+        let s = Template::new("${q | url} ${text | json} ${text | xml}").render(&args);
+
+        assert_eq!(s, "fish%20%26%20chips%2F%C2%A35 it's \\\"so\\\"\\n\\t\\\\ \\u0001 it&apos;s &quot;so&quot;&#10;&#9;\\ \u{1}");
+
+        let s = Template::new("echo ${text | shell} ${cell | csv},${q | csv} ${q | html}").render(&args);
+
+        assert_eq!(s, "echo 'it'\\''s \"so\"\n\t\\ \u{1}' \"a, \"\"b\"\"\",fish & chips/£5 fish &amp; chips/£5");
+    }
+
+    #[test]
+    fn autoescape_modes() {
+        let mut args = HashMap::new();
+        args.insert("q", "a&b c");
+
+        let mut env = Environment::new();
+        env.autoescape(Escape::Url);
+
+        let s = Template::new("/search?q=${q}&x=${q | raw}&h=${q | html}").render_with(&env, &args);
+
+        assert_eq!(s, "/search?q=a%26b%20c&x=a&b c&h=a&amp;b c");
+
+        env.autoescape(Escape::Shell);
+
+        let s = Template::new("grep ${q} file").render_with(&env, &args);
+
+        assert_eq!(s, "grep 'a&b c' file");
+    }
+
+    #[test]
+    fn numbers() {
+        let mut args = HashMap::new();
+        args.insert("amount", "1234567.891");
+        args.insert("small", "-0.004");
+        args.insert("rate", "0.1255");
+        args.insert("half", "2.675");
+        args.insert("nines", "999.995");
+
+        let s = Template::new("${amount | number} ${amount | number(2)} ${amount | number(0, \".\")} ${amount | number(1, \" \", \",\")}").render(&args);
+
+        assert_eq!(s, "1,234,567.891 1,234,567.89 1.234.568 1 234 567,9");
+
+        let s = Template::new("${small | round(2)} ${half | round(2)} ${nines | number(2)} ${rate | percent(1)} ${rate | percent} ${amount | round}").render(&args);
+
+        assert_eq!(s, "0.00 2.68 1,000.00 12.6% 13% 1234568");
+
+        let s = Template::new("${amount | number(2) | sign} ${small | sign} ${half | round | sign} ${small | round(3) | zeropad(8)} ${half | zeropad(6)} ${rate | zeropad(2)}").render(&args);
+
+        assert_eq!(s, "+1,234,567.89 -0.004 +3 -000.004 02.675 0.1255");
+
+        let s = Template::new("${rate | number(1e3)}").try_render(&args);
+
+        assert_eq!(s, Err(Error::Filter("number".into(), "argument 1 must be a whole number, not 1e3".into())));
+
+        args.insert("bad", "12a");
+        let s = Template::new("${bad | number}").try_render(&args);
+
+        assert_eq!(s, Err(Error::Filter("number".into(), "12a is not a number".into())));
+
+        args.insert("exp", "1.5e3");
+        let s = Template::new("${exp | number(1)}").render(&args);
+
+        assert_eq!(s, "1,500.0");
+    }
+
+    #[test]
+    fn dates() {
+        let mut args = HashMap::new();
+        args.insert("epoch", "1700000000");
+        args.insert("before", "-86401");
+        args.insert("iso", "2024-02-29T23:30:00+05:30");
+        args.insert("day", "2024-02-29");
+
+        let s = Template::new("${epoch | date(\"%F %T %a %b %j %u %w %I%p %y\")} ${before | date(\"%F %T %A\")}").render(&args);
+
+        assert_eq!(s, "2023-11-14 22:13:20 Tue Nov 318 2 2 10PM 23 1969-12-30 23:59:59 Tuesday");
+
+        let s = Template::new("${iso | date(\"%d/%m/%Y %H:%M %z %:z %s\")} ${day | date} ${day | date(\"%s\")}").render(&args);
+
+        assert_eq!(s, "29/02/2024 23:30 +0530 +05:30 1709229600 2024-02-29 1709164800");
+
+        args.insert("bad", "2023-02-29");
+        let s = Template::new("${bad | date}").try_render(&args);
+
+        assert_eq!(s, Err(Error::Filter("date".into(), "2023-02-29 is not a date".into())));
+
+        let s = Template::new("${day | date(\"%Q\")}").try_render(&args);
+
+        assert_eq!(s, Err(Error::Filter("date".into(), "unknown format %Q".into())));
+
+        for bad in ["NaN", "inf", "-inf", "1e30", "253402300800", "-62167219201"] {
+            args.insert("bad", bad);
+            let s = Template::new("${bad | date}").try_render(&args);
+
+            assert_eq!(s, Err(Error::Filter("date".into(), format!("{bad} is not a date"))));
+        }
+        args.insert("max", "253402300799.5");
+        args.insert("min", "-62167219200");
+        let s = Template::new("${max | date(\"%F %T\")} ${min | date(\"%F %T\")}").render(&args);
+
+        assert_eq!(s, "9999-12-31 23:59:59 0000-01-01 00:00:00");
+    }
+
+    #[test]
+    fn now() {
+        let mut env = Environment::new();
+        env.clock(|| 86400 * 365);
+        let mut args = HashMap::new();
+
+        let s = Template::new("${now} ${now | date(\"%Y\")}").render_with(&env, &args);
+
+        assert_eq!(s, "1971-01-01T00:00:00Z 1971");
+
+        args.insert("now", "then");
+
+        let s = Template::new("${now}").render_with(&env, &args);
+
+        assert_eq!(s, "then");
+    }
+
+    #[test]
+    fn cases() {
+        let mut args = HashMap::new();
+        args.insert("names", "HTTPServer2Go|getHTTPResponseCode|user_id v2|XMLHttpRequest|iPhone11Pro|  already-kebab--case ");
+
+        let t = |filter: &str| Template::new(&format!("${{for n in names}}${{n | {filter}}} ${{end}}")).render(&args);
+
+        assert_eq!(t("snake_case"), "http_server2_go get_http_response_code user_id_v2 xml_http_request i_phone11_pro already_kebab_case ");
+        assert_eq!(t("camel_case"), "httpServer2Go getHttpResponseCode userIdV2 xmlHttpRequest iPhone11Pro alreadyKebabCase ");
+        assert_eq!(t("pascal_case"), "HttpServer2Go GetHttpResponseCode UserIdV2 XmlHttpRequest IPhone11Pro AlreadyKebabCase ");
+        assert_eq!(t("kebab_case"), "http-server2-go get-http-response-code user-id-v2 xml-http-request i-phone11-pro already-kebab-case ");
+        assert_eq!(t("screaming_snake_case"), "HTTP_SERVER2_GO GET_HTTP_RESPONSE_CODE USER_ID_V2 XML_HTTP_REQUEST I_PHONE11_PRO ALREADY_KEBAB_CASE ");
+        assert_eq!(t("title_case"), "Http Server2 Go Get Http Response Code User Id V2 Xml Http Request I Phone11 Pro Already Kebab Case ");
+    }
+
+    #[test]
+    fn indent() {
+        let mut args = HashMap::new();
+        args.insert("body", "if x:\n    return 1\n\nreturn 2");
+
+        let s = Template::new("def f(x):\n    ${body | indent(4)}\n").render(&args);
+
+        assert_eq!(s, "def f(x):\n    if x:\n        return 1\n\n    return 2");
+
+        let s = Template::new("def f(x):\n  ${body}").render(&args);
+
+        assert_eq!(s, "def f(x):\n  if x:\n    return 1\n\nreturn 2");
+    }
+
+    #[test]
+    fn auto_indent() {
+        let mut env = Environment::new();
+        env.auto_indent(true);
+        let mut args = HashMap::new();
+        args.insert("cmd", "|\n  make\n  make test");
+        args.insert("desc", "two\nlines");
+
+        let s = Template::new("jobs:\n  build:\n    ${!templates/steps.inc}\n    note: ${desc}").render_with(&env, &args);
+
+        assert_eq!(s, "jobs:\n  build:\n    name: test\n    steps:\n      - run: |\n               make\n               make test\n      - run: done\n    note: two\n          lines");
+    }
+
+    #[test]
+    fn text_layout() {
+        let mut args = HashMap::new();
+        args.insert("text", "Größere Übungen sind schön, aber   supercalifragilistic words stay whole\nNew line");
+        args.insert("name", "Zoë");
+
+        let s = Template::new("${text | wrap(16)}").render(&args);
+
+        assert_eq!(s, "Größere Übungen\nsind schön, aber\nsupercalifragilistic\nwords stay whole\nNew line");
+
+        let s = Template::new("${name | truncate(2)}|${text | truncate(10)}|${text | truncate(10, \"...\")}|${name | truncate(3)}").render(&args);
+
+        assert_eq!(s, "Z…|Größere Ü…|Größere...|Zoë");
+
+        let s = Template::new("[${name | pad_left(6)}][${name | pad_right(6, \".\")}][${name | center(8, \"*\")}][${name | center(2)}]").render(&args);
+
+        assert_eq!(s, "[   Zoë][Zoë...][**Zoë***][Zoë]");
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut args = HashMap::new();
+        args.insert("nickname", "");
+        args.insert("username", "fred99");
+
+        let s = Template::new(r#"Hi ${nickname ?? first_name ?? username ?? "guest"}, ${nickname ?? first_name ?? "guest ?? visitor" | upper}"#).render(&args);
+
+        assert_eq!(s, "Hi fred99, GUEST ?? VISITOR");
+
+        args.insert("first_name", "Fred");
+
+        let s = Template::new("${nickname ?? first_name ?? username}").render(&args);
+
+        assert_eq!(s, "Fred");
+
+        let s = Template::new("${q:-what??} ${nickname ?? q:-who?? not me}").render(&args);
+
+        assert_eq!(s, "what?? who?? not me");
+    }
+
+    #[test]
+    fn include_paths() {
+        let mut env = Environment::new();
+        env.include_path("templates").include_path("templates/parts");
+        let mut args = HashMap::new();
+        args.insert("title", "Home");
+
+        // Paths are searched in order, after the including file's directory
+        let s = Template::new("${!nested.inc}|${!title.inc}|${!head.inc}").render_with(&env, &args);
+
+        assert_eq!(s, "<head><title>Home</title></head>\nRoot title|Root title|<head><title>Home</title></head>");
+
+        let s = Template::new("${!test.inc}").render_with(&env, &args);
+
+        assert_eq!(s, "");
+    }
+
+    #[test]
+    fn include_sandbox() {
+        let mut env = Environment::new();
+        env.include_path("templates");
+        let args: HashMap<&str, &str> = HashMap::new();
+        let absolute = std::env::current_dir().unwrap().join("test.inc");
+
+        for file in ["../test.inc", "parts/../../test.inc", absolute.to_str().unwrap()] {
+            let e = Template::new(&format!("${{!{file}}}")).try_render_with(&env, &args);
+
+            assert!(matches!(e, Err(Error::IncludeRejected(ref f, _)) if f == file), "{file}");
+        }
+        let e = Template::new("${extends ../base.inc}").try_render_with(&env, &args);
+
+        assert_eq!(e.unwrap_err().to_string(), "include ../base.inc rejected: parent directory");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn include_symlink() {
+        let dir = std::env::temp_dir().join(format!("stemplate_symlink_{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(dir.join("secret.inc"), "secret").unwrap();
+        std::fs::write(root.join("inside.inc"), "inside").unwrap();
+        let _ = std::os::unix::fs::symlink(dir.join("secret.inc"), root.join("link.inc"));
+        let _ = std::os::unix::fs::symlink(root.join("inside.inc"), root.join("alias.inc"));
+
+        let mut env = Environment::new();
+        env.include_path(&root);
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let s = Template::new("${!alias.inc}").try_render_with(&env, &args);
+
+        assert_eq!(s.unwrap(), "inside");
+
+        let e = Template::new("${!link.inc}").try_render_with(&env, &args);
+
+        assert_eq!(e.unwrap_err(), Error::IncludeRejected("link.inc".into(), "outside the include paths".into()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_policy() {
+        let mut env = Environment::new();
+        let mut policy = IncludePolicy::extensions(&[".tmpl"]);
+        policy.allow("html");
+        env.include_path("templates").include_policy(policy);
+        let mut args = HashMap::new();
+        args.insert("title", "Home");
+
+        let s = Template::new("${!title.html}|${!missing.tmpl}").render_with(&env, &args);
+
+        assert_eq!(s, "<h2>Home</h2>|");
+
+        for file in ["page.inc", "titlehtml", "title.xhtml", "html"] {
+            let e = Template::new(&format!("${{!{file}}}")).try_render_with(&env, &args);
+
+            assert_eq!(e, Err(Error::IncludeRejected(file.into(), "extension not allowed".into())));
+        }
+
+        env.include_policy(IncludePolicy::disabled());
+
+        let e = Template::new("${!page.inc}").try_render_with(&env, &args);
+
+        assert_eq!(e.unwrap_err(), Error::IncludeRejected("page.inc".into(), "includes are disabled".into()));
+
+        let e = Template::new("${extends page.inc}").try_render_with(&env, &args);
+
+        assert!(matches!(e, Err(Error::IncludeRejected(..))));
+    }
+
+    #[test]
+    fn include_args() {
+        let mut env = Environment::new();
+        env.include_path("templates");
+        let mut args = HashMap::new();
+        args.insert("name", "form");
+        args.insert("value", "x@y.com");
+        args.insert("who", "Your name");
+
+        let s = Template::new(r#"${!field.inc name="email" label="E-mail"} ${!field.inc name="user"  label=who value="${name} user"} ${name}"#).render_with(&env, &args);
+
+        assert_eq!(s, r#"<label for="email">E-mail</label><input id="email" value="x@y.com"> <label for="user">Your name</label><input id="user" value="form user"> form"#);
+
+        let s = Template::new(r#"${let name = "set"}${!field.inc name="one two"}${name}"#).render_with(&env, &args);
+
+        assert_eq!(s, r#"<label for="one two"></label><input id="one two" value="x@y.com">set"#);
+    }
+
+    #[test]
+    fn include_cycle() {
+        let mut env = Environment::new();
+        env.include_path("templates");
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let path = |file: &str| Path::new("templates").join(file).canonicalize().unwrap().display().to_string();
+
+        let e = Template::new("${!cycle_a.inc}").try_render_with(&env, &args);
+
+        assert_eq!(e, Err(Error::IncludeCycle(vec![path("cycle_a.inc"), path("cycle_b.inc"), path("cycle_a.inc")])));
+
+        // However the same file is named
+        for file in ["cycle_self.inc", "cycle_dot.inc"] {
+            let e = Template::new(&format!("${{!{file}}}")).try_render_with(&env, &args);
+
+            assert_eq!(e, Err(Error::IncludeCycle(vec![path(file), path(file)])));
+        }
+
+        // Not a cycle to include a file twice
+        let s = Template::new("${!parts/head.inc}${!parts/head.inc}").try_render_with(&env, &args);
+
+        assert_eq!(s.unwrap(), "<head><title></title></head><head><title></title></head>");
+    }
+
+    #[test]
+    fn include_resolver() {
+        let mut templates: HashMap<String, String> = HashMap::new();
+        templates.insert("page.inc".into(), "<${!parts/head.inc}>${!title.inc}".into());
+        templates.insert("parts/head.inc".into(), "${!title.inc}".into());
+        templates.insert("parts/title.inc".into(), "${title}".into());
+        templates.insert("title.inc".into(), "Root".into());
+        templates.insert("loop.inc".into(), "${!parts/loop.inc}".into());
+        templates.insert("parts/loop.inc".into(), "${!loop.inc}".into());
+        let mut env = Environment::new();
+        env.include_resolver(templates);
+        let mut args = HashMap::new();
+        args.insert("title", "Home");
+
+        let s = Template::new("${!page.inc}${!test.inc}").render_with(&env, &args);
+
+        assert_eq!(s, "<Home>Root");
+
+        let e = Template::new("${!loop.inc}").try_render_with(&env, &args);
+
+        assert_eq!(e.unwrap_err(), Error::IncludeCycle(vec!["loop.inc".into(), "parts/loop.inc".into(), "parts/loop.inc".into()]));
+
+        let mut files = FileResolver::new();
+        files.path("templates");
+        env.include_resolver(files);
+
+        let s = Template::new("${!page.inc}").render_with(&env, &args);
+
+        assert_eq!(s, "<h1>Home</h1>");
+    }
+
+    #[test]
+    fn include_raw() {
+        let mut env = Environment::new();
+        env.include_path("templates");
+        let mut args = HashMap::new();
+        args.insert("name", "Fred");
+        args.insert("title", "Example");
+
+        let s = Template::new("${!=snippet.inc} | ${!snippet.inc}").render_with(&env, &args);
+
+        assert_eq!(s, "Use ${name} for the name | Use Fred for the name");
+
+        let s = Template::new("${!example.inc} ${name}").render_with(&env, &args);
+
+        assert_eq!(s, "<pre>Use ${name} for the name</pre> Example Fred");
+
+        // Only the included content is kept as is
+        args.insert("fullname", "${name} Bloggs");
+        args.insert("s", "a|b");
+
+        let s = Template::new("${!=snippet.inc} ${fullname}").render_with(&env, &args);
+
+        assert_eq!(s, "Use ${name} for the name Fred Bloggs");
+
+        let s = Template::new("${for x in s}[${!=snippet.inc}]${end}").render_with(&env, &args);
+
+        assert_eq!(s, "[Use ${name} for the name][Use ${name} for the name]");
+
+        let s = Template::new("${!=snippet.inc | replace(\"Use\", \"Write\") | length} ${!=snippet.inc | upper}").render_with(&env, &args);
+
+        assert_eq!(s, "26 USE ${NAME} FOR THE NAME");
+
+        args.insert("who", "{{name}} Bloggs");
+        let s = Template::new_delimit("{{!=snippet.inc}} {{who}}", "{{", "}}").render_with(&env, &args);
+
+        assert_eq!(s, "Use ${name} for the name Fred Bloggs");
+
+        // Filters see the content itself
+        env.register_filter("dollars", |v, _| Ok::<_, String>(v.matches('$').count().to_string()));
+
+        let s = Template::new("${!=snippet.inc | url}").render_with(&env, &args);
+
+        assert_eq!(s, "Use%20%24%7Bname%7D%20for%20the%20name");
+
+        let s = Template::new("${!=snippet.inc | replace(\"${name}\", \"X\")} ${!=snippet.inc | dollars}").render_with(&env, &args);
+
+        assert_eq!(s, "Use X for the name 1");
+
+        let s = Template::new("${let t = \"${!=snippet.inc}\"}${t | upper} ${let u = \"${!=snippet.inc}\" | lower}${u}").render_with(&env, &args);
+
+        assert_eq!(s, "USE ${NAME} FOR THE NAME use ${name} for the name");
+
+        // Values which happen to contain private use characters are left alone
+        args.insert("icon", "<i class=\"material-icons\">\u{E000}\u{E001}</i>");
+
+        let s = Template::new("${!=snippet.inc} ${=icon}").render_with(&env, &args);
+
+        assert_eq!(s, "Use ${name} for the name <i class=\"material-icons\">\u{E000}\u{E001}</i>");
+    }
+
+    #[test]
+    fn many_defaults() {
+        let mut args = HashMap::new();
+        args.insert("dog", "woofers|rex");
+        args.insert("cat", "kitty|moggi|tiger");
+        args.insert("pets", "${dog:-stray} and ${cat}");
+        args.insert("ids", "a@b:c|d@e:f");
+        args.insert("mail", "${ids}");
+
+        // A list with a default does not limit the shortest
+        let s = Template::new("${*,pets}").render(&args);
+
+        assert_eq!(s, "woofers|rex and kitty,woofers|rex and moggi,woofers|rex and tiger");
+
+        let s = Template::new("${*,pets@longest:-x@product}").render(&args);
+
+        assert_eq!(s, "woofers and kitty,rex and moggi,stray and tiger");
+
+        let s = Template::new("${*,mail}").render(&args);
+
+        assert_eq!(s, "a@b:c,d@e:f");
+    }
+
+    #[test]
+    fn assign_in_loop() {
+        let mut args = HashMap::new();
+        args.insert("s", "a|b|c");
+
+        let s = Template::new(r#"${for x in s}${let x = "z"}${x}${end} ${x}"#).render(&args);
+
+        assert_eq!(s, "abc z");
+
+        let s = Template::new(r#"${let y = "out"}${macro m(y)}${let y = "in"}${y}${end}${call m("arg")} ${y}"#).render(&args);
+
+        assert_eq!(s, "arg out");
+    }
+
+    #[test]
+    fn autoescape_many() {
+        let mut env = Environment::new();
+        env.autoescape(Escape::Html);
+        let mut args = HashMap::new();
+        args.insert("bio", "<script>alert(1)</script>");
+        args.insert("pets", "<rex>|kitty & co");
+        args.insert("row", "<li>${pets}</li>");
+
+        let s = Template::new("${*bio} ${bio} ${*,row} ${*,row | raw}").render_with(&env, &args);
+
+        assert_eq!(s, "&lt;script&gt;alert(1)&lt;/script&gt; &lt;script&gt;alert(1)&lt;/script&gt; &lt;li&gt;&lt;rex&gt;&lt;/li&gt;,&lt;li&gt;kitty &amp; co&lt;/li&gt; <li><rex></li>,<li>kitty & co</li>");
+    }
+
+    #[test]
+    fn count_limit() {
+        let mut args = HashMap::new();
+        args.insert("x", "1.5");
+
+        for filter in ["pad_left", "pad_right", "center", "truncate", "wrap", "number", "round", "percent", "zeropad", "indent"] {
+            let e = Template::new(&format!("${{x | {filter}(4000000000)}}")).try_render(&args);
+
+            assert_eq!(e, Err(Error::Filter(filter.into(), "argument 1 must be at most 1000, not 4000000000".into())));
+        }
+
+        let s = Template::new("${x | zeropad(1000)}").render(&args);
+
+        assert_eq!(s.len(), 1000);
+    }
+
+    #[test]
+    fn auto_indent_loop() {
+        let mut env = Environment::new();
+        env.auto_indent(true);
+        let mut args = HashMap::new();
+        args.insert("s", "a|b");
+        args.insert("body", "l1\nl2");
+
+        let s = Template::new("x:\n  ${for x in s}${body}${end}").render_with(&env, &args);
+
+        assert_eq!(s, "x:\n  l1\n  l2l1\n  l2");
+
+        let s = Template::new("steps:\n  ${for x in s}- name: ${x}\n  run: ${body}\n${end}\n  ${block b}${body}${end}").render_with(&env, &args);
+
+        assert_eq!(s, "steps:\n  - name: a\n    run: l1\n         l2\n  - name: b\n    run: l1\n         l2\n\n  l1\n  l2");
+    }
+
+    #[test]
+    fn for_scope() {
+        let mut args = HashMap::new();
+        args.insert("groups", Value::from(vec!["a|b", "c"]));
+        args.insert("tmpl", Value::from("<${p}>"));
+        args.insert("p", Value::from("outer"));
+
+        let s = Template::new("${for p in groups}${*,tmpl};${end}${p}").render(&args);
+
+        assert_eq!(s, "<a>,<b>;<c>;outer");
+    }
+
+    #[test]
+    fn macro_recursion() {
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let e = Template::new("${macro f()}x${call f()}${end}${call f()}").try_render(&args);
+
+        assert_eq!(e, Err(Error::MacroRecursion(vec!["f".into(), "f".into()])));
+
+        let e = Template::new("${macro a()}${call b()}${end}${macro b()}${call a()}${end}${call a()}").try_render(&args);
+
+        assert_eq!(e.unwrap_err().to_string(), "macro recursion: a -> b -> a");
+
+        // Calling a macro again, but not within itself, is fine
+        let s = Template::new("${macro b(x)}[${x}]${end}${macro a(x)}${call b(x)}${call b(x)}${end}${call a(\"1\")}${call a(\"2\")}").try_render(&args);
+
+        assert_eq!(s.unwrap(), "[1][1][2][2]");
+    }
+
+    #[test]
+    fn comment() {
+        let mut args = HashMap::new();
+        args.insert("name", "Charles");
+
+        let s = Template::new("Hello ${-- who, ${name} or } --}${name}${----}\n${-- spans\nlines --}!").render(&args);
+
+        assert_eq!(s, "Hello Charles\n!");
+    }
+
+    #[test]
+    fn include() {
+        let mut args = HashMap::new();
+        args.insert("example", "text");
+        let s = Template::new("File contains: ${!test.inc}").render(&args);
+
+        assert_eq!(s, "File contains: inc text");
+    }
+
+    #[test]
+    fn extend() {
+        let mut args = HashMap::new();
+        args.insert("name", "Charles");
+
+        let s = Template::new("${extends templates/base.inc}ignored${block content}Hello ${name}${end}").render(&args);
+
+        assert_eq!(s, "<title>Site</title>\n<main>Hello Charles</main>");
+    }
+
+    #[test]
+    fn extend_super() {
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let s = Template::new("${extends templates/layout.inc}${block title}Page - ${super}${end}${block content}Text${end}").render(&args);
+
+        assert_eq!(s, "<title>Page - Layout - Site</title>\n<main>Text</main>");
+
+        let s = Template::new("${extends templates/layout.inc}").render(&args);
+
+        assert_eq!(s, "<title>Layout - Site</title>\n<main><nav/>Nothing here</main>");
+    }
+
+    #[test]
+    fn dont_include() {
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let e = Template::new("${!/etc/passwd}").try_render(&args);
+
+        assert_eq!(e, Err(Error::IncludeRejected("/etc/passwd".into(), "extension not allowed".into())));
+
+        // A rejected include fails the whole render, render() gives nothing
+        let t = Template::new("Hello ${!notes.txt} there");
+
+        assert!(matches!(t.try_render(&args), Err(Error::IncludeRejected(..))));
+        assert_eq!(t.render(&args), "");
+    }
+
+    #[test]
+    fn include_keeps_text() {
+        let args: HashMap<&str, &str> = HashMap::new();
+
+        let s = Template::new("Hello ${!missing.inc} there").try_render(&args);
+
+        assert_eq!(s.unwrap(), "Hello  there");
+
+        let s = Template::new("Hello ${!=templates/snippet.inc} there").try_render(&args);
+
+        assert_eq!(s.unwrap(), "Hello Use ${name} for the name there");
+    }
+
+    #[test]
+    fn many() {
+        let mut args = HashMap::new();
+        args.insert("dog", "woofers|rex");
+        args.insert("cat", "kitty|moggi|tiger");
+        args.insert("pets", "${dog} and ${cat}");
+
+        let s = Template::new("${*pets}").render(&args);
+
+        assert_eq!(s, "woofers and kitty\nrex and moggi");
+    }
+
+    #[test]
+    fn many_delim() {
+        let mut args = HashMap::new();
+        args.insert("dog", "woofers|rex");
+        args.insert("cat", "kitty|moggi");
+        args.insert("pets", "${dog} and ${cat}");
+
+        let s = Template::new("${*|pets}").render(&args);
+
+        assert_eq!(s, "woofers and kitty|rex and moggi");
+    }
+
+    #[test]
+    fn many_delim2() {
+        let mut args = HashMap::new();
+        args.insert("dog", "woofers|rex");
+        args.insert("cat", "kitty|moggi");
+        args.insert("rabbit", "cuddly");
+        args.insert("pets", "${dog}, ${cat} and ${rabbit}");
+
+        let s = Template::new("I love ${*;pets} a lot").render(&args);
+
+        assert_eq!(s, "I love woofers, kitty and cuddly;rex, moggi and cuddly a lot");
+    }
+
+    #[test]
+    fn many_delim3() {
+        let mut args = HashMap::new();
+        args.insert("marg", "arg0");
+        args.insert("top", "${marg}");
+
+        let s = Template::new("[${*,top}]").render(&args);
+
+        assert_eq!(s, "[arg0]");
+    }
+
+    #[test]
+    fn many_delim4() {
+        let mut args = HashMap::new();
+        args.insert("marg", "arg0");
+        args.insert("mand_args", r#""${marg}""#);
+        args.insert("func", r#"[${*,mand_args}]"#);
+
+        let s = Template::new("${func}").render(&args);
+        //let s = Template::new(r#"[${*,mand_args}]"#).render(&args);
+
+        assert_eq!(s, "[\"arg0\"]");
+    }
+
+    #[test]
+    fn for_loop() {
+        let mut args = HashMap::new();
+        args.insert("pets", "rex|kitty|moggi");
+
+        let s = Template::new("${for pet in pets}${loop.index}/${loop.length}:${pet}${?loop.last=false:-,}${end}").render(&args);
+
+        assert_eq!(s, "1/3:rex,2/3:kitty,3/3:moggi");
+    }
+
+    #[test]
+    fn for_loop_first() {
+        let mut args = HashMap::new();
+        args.insert("pets", "rex|kitty");
+        args.insert("pet", "none");
+
+        let s = Template::new("<${for pet in pets}${?loop.first=false:-;}${pet}${end}> ${pet}").render(&args);
+
+        assert_eq!(s, "<rex;kitty> none");
+    }
+
+    #[test]
+    fn for_loop_else() {
+        let mut args = HashMap::new();
+        args.insert("pets", "");
+
+        let s = Template::new("Pets: ${for pet in pets}${pet} ${else}none${end}.").render(&args);
+
+        assert_eq!(s, "Pets: none.");
+    }
+
+    #[test]
+    fn for_loop_nested() {
+        let mut args = HashMap::new();
+        args.insert("rows", "a|b");
+        args.insert("cols", "1|2");
+
+        let s = Template::new("${for r in rows}[${for c in cols}${r}${c}${loop.index0}${end}]${end}").render(&args);
+
+        assert_eq!(s, "[a10a21][b10b21]");
+    }
+
+    #[test]
+    fn for_records() {
+        let rows: Vec<Value> = [("Pen", "1.20"), ("Ink", "3.50")].iter()
+            .map(|(name, price)| {
+                let mut row = HashMap::new();
+                row.insert("name", *name);
+                row.insert("price", *price);
+                Value::from(row)
+            })
+            .collect();
+        let mut args = HashMap::new();
+        args.insert("rows", Value::from(rows));
+        args.insert("title", Value::from("Stock"));
+
+        let s = Template::new("${title}:${for row in rows} ${row.name}=${row.price}${end} (${rows.1.name})").render(&args);
+
+        assert_eq!(s, "Stock: Pen=1.20 Ink=3.50 (Ink)");
+    }
+
+    #[test]
+    fn for_entries() {
+        let mut settings = BTreeMap::new();
+        settings.insert("debug", "off");
+        settings.insert("colour", "blue");
+        let mut args = HashMap::new();
+        args.insert("settings", Value::from(settings));
+
+        let s = Template::new("${for k, v in settings}${k}=${v};${end} ${for k in settings}${k}${end}").render(&args);
+
+        assert_eq!(s, "colour=blue;debug=off; colourdebug");
+    }
+
+    #[test]
+    fn for_list_index() {
+        let mut args = HashMap::new();
+        args.insert("pets", "rex|kitty");
+
+        let s = Template::new("${for i, pet in pets}${i}:${pet} ${end}").render(&args);
+
+        assert_eq!(s, "0:rex 1:kitty ");
+    }
+
+    #[test]
+    fn many_longest() {
+        let mut args = HashMap::new();
+        args.insert("dog", "woofers|rex");
+        args.insert("cat", "kitty|moggi|tiger");
+        args.insert("pets", "${dog:-stray} and ${cat}");
+
+        let s = Template::new("${*,pets@longest}").render(&args);
+
+        assert_eq!(s, "woofers and kitty,rex and moggi,stray and tiger");
+    }
+
+    #[test]
+    fn many_strict() {
+        let mut args = HashMap::new();
+        args.insert("dog", "woofers|rex");
+        args.insert("cat", "kitty|moggi");
+        args.insert("pets", "${dog} and ${cat}");
+
+        let s = Template::new("${*,pets@strict}").try_render(&args);
+
+        assert_eq!(s, Ok("woofers and kitty,rex and moggi".to_string()));
+
+        args.insert("cat", "kitty|moggi|tiger");
+
+        let s = Template::new("${*,pets@strict}").try_render(&args);
+
+        assert_eq!(s, Err(Error::LengthMismatch(vec![("dog".into(), 2), ("cat".into(), 3)])));
+        assert_eq!(Template::new("${*,pets@strict}").render(&args), "");
+    }
+
+    #[test]
+    fn many_product() {
+        let mut args = HashMap::new();
+        args.insert("size", "S|M");
+        args.insert("colour", "red|blue|green");
+        args.insert("sku", "${colour}-${size}");
+
+        let s = Template::new("${* sku@product}").render(&args);
+
+        assert_eq!(s, "red-S red-M blue-S blue-M green-S green-M");
     }
 
     #[test]